@@ -0,0 +1,223 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::storage::{MemoryStorage, Storage};
+
+/// Key the access lists are stored under
+const STORAGE_KEY: &str = "access";
+
+/// Who may use the bot
+/// Denied users and chats are always refused. When nothing is on the allowlists everyone
+/// else may use the bot, otherwise only allowed users and members of allowed chats may
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessLists {
+    #[serde(default)]
+    pub allowed_users: BTreeSet<i64>,
+    #[serde(default)]
+    pub denied_users: BTreeSet<i64>,
+    #[serde(default)]
+    pub allowed_chats: BTreeSet<i64>,
+    #[serde(default)]
+    pub denied_chats: BTreeSet<i64>,
+}
+
+/// Whether `/allow` and `/deny` are about a user or a chat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    User(i64),
+    Chat(i64),
+}
+
+/// Access control, admins can change the lists at runtime and the changes are persisted
+/// The configured lists are merged into the stored ones on every start. A configured deny
+/// always wins, not even `/allow` lifts it, otherwise the latest change wins
+#[derive(Debug)]
+pub struct Access {
+    lists: AccessLists,
+    /// The configured lists, only their denies are checked again
+    configured: AccessLists,
+    admins: BTreeSet<i64>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access {
+            lists: AccessLists::default(),
+            configured: AccessLists::default(),
+            admins: BTreeSet::new(),
+            storage: Arc::new(MemoryStorage::default()),
+        }
+    }
+}
+
+impl Access {
+    /// Load the access lists from `storage` and merge the configured ones into them
+    /// Configured allows don't undo a deny made at runtime, configured denies undo any allow
+    pub fn load(
+        storage: Arc<dyn Storage>,
+        configured: AccessLists,
+        admins: BTreeSet<i64>,
+    ) -> Result<Self, Error> {
+        let mut lists: AccessLists = storage.load(STORAGE_KEY)?.unwrap_or_default();
+        let merge = |allowed: &mut BTreeSet<i64>,
+                     denied: &mut BTreeSet<i64>,
+                     allow: &BTreeSet<i64>,
+                     deny: &BTreeSet<i64>| {
+            allowed.extend(allow.difference(denied));
+            denied.extend(deny);
+            allowed.retain(|id| !deny.contains(id));
+        };
+        merge(
+            &mut lists.allowed_users,
+            &mut lists.denied_users,
+            &configured.allowed_users,
+            &configured.denied_users,
+        );
+        merge(
+            &mut lists.allowed_chats,
+            &mut lists.denied_chats,
+            &configured.allowed_chats,
+            &configured.denied_chats,
+        );
+        Ok(Access {
+            lists,
+            configured,
+            admins,
+            storage,
+        })
+    }
+
+    pub fn is_admin(&self, user_id: i64) -> bool {
+        self.admins.contains(&user_id)
+    }
+
+    /// Whether a user may use the bot in a chat, admins always may
+    pub fn is_allowed(&self, user_id: i64, chat_id: i64) -> bool {
+        let lists = &self.lists;
+        if self.is_admin(user_id) {
+            return true;
+        }
+        if lists.denied_users.contains(&user_id)
+            || lists.denied_chats.contains(&chat_id)
+            || self.configured.denied_users.contains(&user_id)
+            || self.configured.denied_chats.contains(&chat_id)
+        {
+            return false;
+        }
+        (lists.allowed_users.is_empty() && lists.allowed_chats.is_empty())
+            || lists.allowed_users.contains(&user_id)
+            || lists.allowed_chats.contains(&chat_id)
+    }
+
+    /// Allow a user or chat, taking it off the denylist, and persist the change
+    pub fn allow(&mut self, target: Target) -> Result<(), Error> {
+        let lists = &mut self.lists;
+        match target {
+            Target::User(id) => {
+                lists.denied_users.remove(&id);
+                lists.allowed_users.insert(id);
+            }
+            Target::Chat(id) => {
+                lists.denied_chats.remove(&id);
+                lists.allowed_chats.insert(id);
+            }
+        }
+        self.save()
+    }
+
+    /// Deny a user or chat, taking it off the allowlist, and persist the change
+    pub fn deny(&mut self, target: Target) -> Result<(), Error> {
+        let lists = &mut self.lists;
+        match target {
+            Target::User(id) => {
+                lists.allowed_users.remove(&id);
+                lists.denied_users.insert(id);
+            }
+            Target::Chat(id) => {
+                lists.allowed_chats.remove(&id);
+                lists.denied_chats.insert(id);
+            }
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        self.storage.store(STORAGE_KEY, &self.lists)
+    }
+
+    /// The admins and access lists, formatted for `/users`
+    pub fn describe(&self) -> String {
+        let lists = &self.lists;
+        let mut text = format!(
+            "admins: {}\nallowed users: {}\ndenied users: {}\nallowed chats: {}\ndenied chats: {}",
+            list_ids(&self.admins),
+            list_ids(&lists.allowed_users),
+            list_ids(&lists.denied_users),
+            list_ids(&lists.allowed_chats),
+            list_ids(&lists.denied_chats),
+        );
+        if lists.allowed_users.is_empty() && lists.allowed_chats.is_empty() {
+            text.push_str("\nthe allowlists are empty, so everyone who isn't denied may use me.");
+        }
+        text
+    }
+}
+
+fn list_ids(ids: &BTreeSet<i64>) -> String {
+    if ids.is_empty() {
+        return "none".to_string();
+    }
+    ids.iter()
+        .map(i64::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_lists() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut access =
+            Access::load(storage.clone(), AccessLists::default(), BTreeSet::from([1])).unwrap();
+
+        // Open to everyone until something is allowed
+        assert!(access.is_allowed(2, -100));
+        access.allow(Target::Chat(-100)).unwrap();
+        assert!(access.is_allowed(2, -100));
+        assert!(!access.is_allowed(2, 2));
+
+        // Denying wins over an allowed chat, but never locks out an admin
+        access.deny(Target::User(2)).unwrap();
+        access.deny(Target::User(1)).unwrap();
+        assert!(!access.is_allowed(2, -100));
+        assert!(access.is_allowed(1, 1));
+
+        // The configured lists are merged into the persisted ones, a configured allow doesn't
+        // undo a deny made at runtime
+        let configured = AccessLists {
+            allowed_users: BTreeSet::from([2, 3]),
+            ..Default::default()
+        };
+        let access = Access::load(storage.clone(), configured, BTreeSet::new()).unwrap();
+        assert!(access.is_allowed(3, 3));
+        assert!(access.is_allowed(4, -100));
+        assert!(!access.is_allowed(2, -100));
+
+        // A deny added to the configuration later applies, and can't be lifted with /allow
+        let configured = AccessLists {
+            denied_chats: BTreeSet::from([-100]),
+            ..Default::default()
+        };
+        let mut access = Access::load(storage, configured, BTreeSet::new()).unwrap();
+        assert!(!access.is_allowed(4, -100));
+        access.allow(Target::Chat(-100)).unwrap();
+        assert!(!access.is_allowed(4, -100));
+    }
+}
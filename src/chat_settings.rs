@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::model::Model;
+use crate::params::SamplingParams;
+use crate::storage::{MemoryStorage, Storage};
+
+/// Settings for a single chat
+/// `users` holds per-user overrides, only used when per-user model selection is enabled
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ChatSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<Model>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub users: HashMap<i64, Model>,
+    /// Name of the persona picked with `/persona`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    /// Replaces the configured system prompt in this chat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Sampling parameters set with `/params`
+    #[serde(default, skip_serializing_if = "SamplingParams::is_empty")]
+    pub params: SamplingParams,
+}
+
+/// Key the settings are stored under
+const STORAGE_KEY: &str = "chats";
+
+/// Settings of every chat the bot is in, persisted so they survive a restart
+#[derive(Debug)]
+pub struct Settings {
+    chats: HashMap<i64, ChatSettings>,
+    storage: Arc<dyn Storage>,
+    per_user: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            chats: HashMap::new(),
+            storage: Arc::new(MemoryStorage::default()),
+            per_user: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Load the settings from `storage`, nothing stored yet just means no chat has settings
+    pub fn load(storage: Arc<dyn Storage>, per_user: bool) -> Result<Self, Error> {
+        Ok(Settings {
+            chats: storage.load(STORAGE_KEY)?.unwrap_or_default(),
+            storage,
+            per_user,
+        })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        self.storage.store(STORAGE_KEY, &self.chats)
+    }
+
+    /// The settings of a chat, `None` if nothing was changed in it yet
+    pub fn chat(&self, chat_id: i64) -> Option<&ChatSettings> {
+        self.chats.get(&chat_id)
+    }
+
+    /// Change the settings of a chat and persist the change
+    pub fn update(
+        &mut self,
+        chat_id: i64,
+        change: impl FnOnce(&mut ChatSettings),
+    ) -> Result<(), Error> {
+        change(self.chats.entry(chat_id).or_default());
+        self.save()
+    }
+
+    /// The model selected for this chat, or for this user in this chat when per-user
+    /// selection is enabled. Falls back to the chat's model and then to `default`
+    pub fn model(&self, chat_id: i64, user_id: i64, default: &Model) -> Model {
+        let Some(chat) = self.chats.get(&chat_id) else {
+            return default.clone();
+        };
+        self.per_user
+            .then(|| chat.users.get(&user_id))
+            .flatten()
+            .or(chat.model.as_ref())
+            .unwrap_or(default)
+            .clone()
+    }
+
+    /// Select a model for this chat (or this user in this chat) and persist the change
+    pub fn set_model(&mut self, chat_id: i64, user_id: i64, model: Model) -> Result<(), Error> {
+        let per_user = self.per_user;
+        self.update(chat_id, |chat| {
+            if per_user {
+                chat.users.insert(user_id, model);
+            } else {
+                chat.model = Some(model);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_selection_scope() {
+        let claude = Model::new("anthropic/claude-3.5-sonnet");
+        let default = Model::new("openai/gpt-4o");
+
+        let mut settings = Settings::default();
+        settings.set_model(1, 10, claude.clone()).unwrap();
+        assert_eq!(settings.model(1, 20, &default), claude);
+        assert_eq!(settings.model(2, 10, &default), default);
+
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut settings = Settings::load(storage.clone(), true).unwrap();
+        settings.set_model(1, 10, claude.clone()).unwrap();
+        assert_eq!(settings.model(1, 10, &default), claude);
+        assert_eq!(settings.model(1, 20, &default), default);
+
+        // The selection is persisted, so it is still there after loading again
+        let settings = Settings::load(storage, true).unwrap();
+        assert_eq!(settings.model(1, 10, &default), claude);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::constants::TELEGRAM_MESSAGE_LIMIT;
+use crate::error::Error;
+use crate::messages::bot_messages;
+use crate::messages::telegram::Destination;
+use crate::telegram_bot::TgBot;
+
+/// Characters kept free in every chunk of a text with code blocks, to close a code block
+/// that has to be cut in two
+const FENCE_RESERVE: usize = 4;
+
+/// Boundaries to cut at, from most to least preferred
+const SEPARATORS: &[&str] = &["\n\n", "\n", ". ", "! ", "? ", " "];
+
+/// Split a text into chunks of at most `limit` UTF-16 code units, the way Telegram counts
+/// Cuts at paragraph, line, sentence or word boundaries, in that order of preference.
+/// A text is cut right before a code block rather than inside it; when a code block doesn't
+/// fit in one chunk at all it is closed at the end of the chunk and reopened in the next
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let reserve = if text.contains("```") {
+        FENCE_RESERVE
+    } else {
+        0
+    };
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+    let mut reopen = String::new();
+
+    while !rest.is_empty() {
+        let prefix = std::mem::take(&mut reopen);
+        if utf16_len(&prefix) + utf16_len(rest) <= limit {
+            chunks.push(prefix + rest);
+            break;
+        }
+
+        let budget = limit.saturating_sub(utf16_len(&prefix) + reserve).max(1);
+        // Always take at least one character, even one that needs two code units
+        let window_end =
+            utf16_prefix(rest, budget).max(rest.chars().next().map_or(0, char::len_utf8));
+        let (head, tail) = rest.split_at(find_cut(&rest[..window_end], !prefix.is_empty()));
+
+        let mut chunk = prefix + head.trim_end();
+        match open_fence(&chunk, false).map(|(_, fence)| format!("{}\n", fence)) {
+            Some(fence) => {
+                chunk.push_str("\n```");
+                reopen = fence;
+                rest = tail.trim_start_matches('\n');
+            }
+            None => rest = tail.trim_start(),
+        }
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// The byte offset of the longest start of `text` that takes at most `units` UTF-16 code units
+fn utf16_prefix(text: &str, units: usize) -> usize {
+    let mut used = 0;
+    for (index, c) in text.char_indices() {
+        used += c.len_utf16();
+        if used > units {
+            return index;
+        }
+    }
+    text.len()
+}
+
+/// Where to cut a window of text, as a byte offset
+/// `in_code` tells that the window continues a code block from the previous chunk
+fn find_cut(window: &str, in_code: bool) -> usize {
+    match open_fence(window, in_code) {
+        // A code block starts in this window but doesn't end in it, keep it in one piece
+        Some((start, _)) if start > 0 && !in_code => return start,
+        // Inside a code block only lines are safe to cut at
+        Some(_) => {
+            return window
+                .rfind('\n')
+                .filter(|&i| i > 0)
+                .map_or(window.len(), |i| i + 1)
+        }
+        None => {}
+    }
+
+    // Don't cut so early that we end up with lots of tiny chunks
+    let min = window.len() / 2;
+    SEPARATORS
+        .iter()
+        .find_map(|separator| {
+            window
+                .rfind(separator)
+                .map(|i| i + separator.len())
+                .filter(|&i| i >= min)
+        })
+        .unwrap_or(window.len())
+}
+
+/// The byte offset and line of the code block fence left open at the end of `text`, if any
+/// `in_code` tells that `text` starts inside a code block
+fn open_fence(text: &str, in_code: bool) -> Option<(usize, &str)> {
+    let mut open = in_code.then_some((0, ""));
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            open = match open {
+                Some(_) => None,
+                None => Some((offset, line.trim())),
+            };
+        }
+        offset += line.len();
+    }
+    open
+}
+
+impl TgBot {
+    /// Send a reply that may be longer than Telegram allows in one message
+    /// It is split over several messages, or sent as a markdown document when that would
+    /// take more than `document_after_chunks` messages. Only the first message is a reply
+    pub async fn send_reply(&self, to: &Destination, text: &str) -> Result<(), Error> {
+        self.send_chunks(to, text, false).await
+    }
+
+    /// Send a model's answer like `send_reply`, with its Markdown rendered
+    pub async fn send_answer(&self, to: &Destination, text: &str) -> Result<(), Error> {
+        self.send_chunks(to, text, true).await
+    }
+
+    async fn send_chunks(&self, to: &Destination, text: &str, markdown: bool) -> Result<(), Error> {
+        let chunks = split_message(text, TELEGRAM_MESSAGE_LIMIT);
+        if self.as_document(&chunks) {
+            return self
+                .send_document(
+                    to,
+                    "answer.md",
+                    text.as_bytes().to_vec(),
+                    bot_messages::ANSWER_AS_DOCUMENT,
+                )
+                .await;
+        }
+        let mut to = *to;
+        for chunk in chunks {
+            // Every chunk is rendered on its own, the split closes and reopens code blocks
+            if markdown {
+                self.send_markdown(&to, &chunk).await?;
+            } else {
+                self.send_message(&to, &chunk).await?;
+            }
+            to = to.without_reply();
+        }
+        Ok(())
+    }
+
+    /// Finish an answer whose beginning is already shown in `message_id` as `shown`
+    /// The message is edited to hold the first chunk and the other chunks follow as new
+    /// messages, or the whole answer is sent as a document when it is too long. `message_id`
+    /// was sent to `to`, so what follows it doesn't reply again
+    pub async fn finish_reply(
+        &self,
+        to: &Destination,
+        message_id: i64,
+        shown: &str,
+        text: &str,
+    ) -> Result<(), Error> {
+        let (chat_id, rest) = (to.chat_id, to.without_reply());
+        let chunks = split_message(text, TELEGRAM_MESSAGE_LIMIT);
+        if self.as_document(&chunks) {
+            self.edit_message_text(chat_id, message_id, bot_messages::ANSWER_AS_DOCUMENT)
+                .await?;
+            return self
+                .send_document(&rest, "answer.md", text.as_bytes().to_vec(), "")
+                .await;
+        }
+
+        let mut chunks = chunks.into_iter();
+        if let Some(first) = chunks.next() {
+            // Telegram refuses edits that don't change the text
+            if first != shown {
+                self.edit_message_markdown(chat_id, message_id, &first)
+                    .await?;
+            }
+        }
+        for chunk in chunks {
+            self.send_markdown(&rest, &chunk).await?;
+        }
+        Ok(())
+    }
+
+    fn as_document(&self, chunks: &[String]) -> bool {
+        self.cfg()
+            .document_after_chunks
+            .is_some_and(|max| chunks.len() > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message("short answer", 20), vec!["short answer"]);
+
+        // Paragraphs are preferred over sentences and words
+        let text = "First paragraph here.\n\nSecond one. It has two sentences.";
+        assert_eq!(
+            split_message(text, 40),
+            vec!["First paragraph here.", "Second one. It has two sentences."]
+        );
+
+        // Without any boundary the text is cut hard, on a character boundary
+        let chunks = split_message(&"ä".repeat(25), 10);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 10));
+
+        // Telegram counts UTF-16 code units, characters outside the BMP count twice
+        let chunks = split_message(&"🐸".repeat(25), 10);
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|chunk| utf16_len(chunk) <= 10));
+        assert_eq!(chunks.concat(), "🐸".repeat(25));
+
+        // A code block that doesn't fit is moved to the next chunk as a whole
+        let text = "Some intro text.\n```rust\nfn main() {}\n```\nDone.";
+        let chunks = split_message(text, 35);
+        assert_eq!(chunks[0], "Some intro text.");
+        assert_eq!(chunks[1], "```rust\nfn main() {}\n```\nDone.");
+
+        // A code block that doesn't fit at all is closed and reopened
+        let text = "```rust\nlet a = 1;\nlet b = 2;\nlet c = 3;\n```";
+        let chunks = split_message(text, 30);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(utf16_len(chunk) <= 30, "{:?}", chunk);
+            assert!(chunk.starts_with("```rust\n"), "{:?}", chunk);
+            assert!(chunk.ends_with("```"), "{:?}", chunk);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::BTreeSet;
+
+use crate::commands::{Scope, COMMANDS};
+use crate::error::Error;
+use crate::messages::telegram::BotCommand;
+use crate::telegram_bot::TgBot;
+
+impl TgBot {
+    /// Register the command menu with Telegram, so clients show the commands and complete them
+    /// There is a menu for private chats, one for groups and one for the private chat of every
+    /// admin, each in English and in every language a description is translated to
+    pub async fn register_commands(&self) -> Result<(), Error> {
+        let languages: BTreeSet<&str> = COMMANDS
+            .iter()
+            .flat_map(|info| info.translations.iter().map(|(code, _)| *code))
+            .collect();
+        let languages: Vec<Option<&str>> = std::iter::once(None)
+            .chain(languages.into_iter().map(Some))
+            .collect();
+
+        let mut menus = vec![
+            (
+                serde_json::json!({ "type": "all_private_chats" }),
+                vec![Scope::Private],
+            ),
+            (
+                serde_json::json!({ "type": "all_group_chats" }),
+                vec![Scope::Group],
+            ),
+        ];
+        for admin in &self.cfg().admins {
+            menus.push((
+                serde_json::json!({ "type": "chat", "chat_id": admin }),
+                vec![Scope::Private, Scope::Admin],
+            ));
+        }
+
+        for (scope, scopes) in &menus {
+            for language in &languages {
+                let commands: Vec<BotCommand> = COMMANDS
+                    .iter()
+                    .filter(|info| info.scopes.iter().any(|scope| scopes.contains(scope)))
+                    .map(|info| BotCommand {
+                        command: info.name.to_string(),
+                        description: info.description(*language).to_string(),
+                    })
+                    .collect();
+                let mut body = serde_json::json!({
+                    "commands": commands,
+                    "scope": scope,
+                });
+                if let Some(language) = language {
+                    body["language_code"] = (*language).into();
+                }
+                let result: Result<bool, Error> = self
+                    .call_telegram("setMyCommands", |request| Ok(request.json(&body)))
+                    .await;
+                // Registering for an admin fails until they've started a chat with the bot,
+                // which shouldn't keep the other menus from being registered
+                if let Err(e) = result {
+                    if scope["type"] == "chat" {
+                        tracing::warn!(?e, ?scope, "Failed to register admin commands");
+                    } else {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
-use crate::error::Error;
+use crate::access::Target;
+use crate::error::{ArgError, Error};
 use crate::messages::bot_messages;
+use crate::messages::telegram::{Destination, Message, MessageEntity};
 use crate::telegram_bot::TgBot;
+use crate::usage::Span;
+use crate::utils::utf16_to_byte;
 
-pub trait CommandTrait: for<'a> TryFrom<&'a str> {
-    async fn execute(&self, bot: &mut TgBot, chat_id: i64) -> Result<(), Error>;
+pub trait CommandTrait: for<'a> TryFrom<CommandText<'a>> {
+    async fn execute(&self, bot: &TgBot, message: &Message) -> Result<(), Error>;
 }
 
 pub enum Command {
@@ -12,47 +16,654 @@ pub enum Command {
     Model,
     Frog(String),
     ChangeModel(String),
+    System(SystemAction),
+    Persona(PersonaAction),
+    Params(ParamsAction),
+    Allow(TargetArg),
+    Deny(TargetArg),
+    Users,
+    Usage(UsageAction),
+    Help(Option<&'static CommandInfo>),
     Unknown,
 }
 
-impl<'a> TryFrom<&'a str> for Command {
-    type Error = Error;
+/// The command menus Telegram shows, see `TgBot::register_commands`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// Private chats with the bot
+    Private,
+    /// Groups and supergroups
+    Group,
+    /// The private chats of admins, on top of the private chat commands
+    Admin,
+}
+
+/// Offered in every chat
+const EVERYWHERE: &[Scope] = &[Scope::Private, Scope::Group];
+
+/// What the command menu and `/help` know about a command
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// The arguments as shown after the command, e.g. `<model>` or `[list | <name>]`
+    pub usage: &'static str,
+    pub description: &'static str,
+    /// What every argument means
+    pub arguments: &'static [(&'static str, &'static str)],
+    /// The description in other languages, by IETF language code
+    pub translations: &'static [(&'static str, &'static str)],
+    /// The menus the command is listed in
+    pub scopes: &'static [Scope],
+}
+
+impl CommandInfo {
+    /// The description in a language, English when there is no translation
+    pub fn description(&self, language: Option<&str>) -> &'static str {
+        self.translations
+            .iter()
+            .find(|(code, _)| Some(*code) == language)
+            .map_or(self.description, |(_, description)| description)
+    }
+
+    pub fn admin_only(&self) -> bool {
+        self.scopes == [Scope::Admin]
+    }
+
+    /// The command with its arguments, e.g. `/change_model <model>`
+    pub fn usage(&self) -> String {
+        format!("/{} {}", self.name, self.usage)
+            .trim_end()
+            .to_string()
+    }
+
+    /// Everything about the command, for `/help <command>`
+    pub fn help(&self, language: Option<&str>) -> String {
+        let mut help = format!("{}\n{}", self.usage(), self.description(language));
+        for (argument, meaning) in self.arguments {
+            help.push_str(&format!("\n- {}: {}", argument, meaning));
+        }
+        if self.admin_only() {
+            help.push_str("\nonly admins can use this command.");
+        }
+        help
+    }
+
+    pub fn find(name: &str) -> Option<&'static CommandInfo> {
+        COMMANDS.iter().find(|info| info.name == name)
+    }
+}
+
+/// The list of commands for `/help`, admin commands are only listed for admins
+pub fn help(language: Option<&str>, admin: bool) -> String {
+    let line = |info: &CommandInfo| format!("\n{} - {}", info.usage(), info.description(language));
+    let mut help = String::from("here's what i can do:");
+    COMMANDS
+        .iter()
+        .filter(|info| !info.admin_only())
+        .for_each(|info| help.push_str(&line(info)));
+    if admin {
+        help.push_str("\n\nadmin commands:");
+        COMMANDS
+            .iter()
+            .filter(|info| info.admin_only())
+            .for_each(|info| help.push_str(&line(info)));
+    }
+    help.push_str("\n\ntype /help <command> to learn more about a command.");
+    help
+}
+
+/// Every command the bot understands, in the order they are listed
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "frog",
+        usage: "<question>",
+        description: "Ask the model a question",
+        arguments: &[(
+            "question",
+            "what to ask, earlier questions in this chat are remembered",
+        )],
+        translations: &[("nl", "Stel het model een vraag")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "startfrog",
+        usage: "",
+        description: "Say hello",
+        arguments: &[],
+        translations: &[("nl", "Zeg hallo")],
+        scopes: &[Scope::Private],
+    },
+    CommandInfo {
+        name: "help",
+        usage: "[command]",
+        description: "Show what the commands do",
+        arguments: &[("command", "the command to explain, e.g. frog")],
+        translations: &[("nl", "Laat zien wat de commando's doen")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "model",
+        usage: "",
+        description: "Show the model in use",
+        arguments: &[],
+        translations: &[("nl", "Laat zien welk model gebruikt wordt")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "list_models",
+        usage: "",
+        description: "List the available models",
+        arguments: &[],
+        translations: &[("nl", "Toon de beschikbare modellen")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "change_model",
+        usage: "<model>",
+        description: "Switch to another model",
+        arguments: &[(
+            "model",
+            "an alias from /list_models or an exact OpenRouter slug",
+        )],
+        translations: &[("nl", "Wissel van model")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "persona",
+        usage: "[list | <name>]",
+        description: "Show, list or switch personas",
+        arguments: &[
+            ("list", "show the available personas"),
+            ("name", "the persona to switch to"),
+        ],
+        translations: &[("nl", "Toon, bekijk of wissel van persona")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "system",
+        usage: "[reset | <prompt>]",
+        description: "Show, set or reset the system prompt",
+        arguments: &[
+            ("reset", "go back to the default system prompt"),
+            ("prompt", "the new system prompt of this chat"),
+        ],
+        translations: &[("nl", "Toon, wijzig of herstel de systeemprompt")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "params",
+        usage: "[reset | <name> <value>]",
+        description: "Show or change sampling parameters",
+        arguments: &[
+            ("reset", "go back to the default parameters"),
+            ("name", "a parameter, e.g. temperature, top_p or max_tokens"),
+            ("value", "the new value, or reset to go back to the default"),
+        ],
+        translations: &[("nl", "Toon of wijzig de samplingparameters")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "usage",
+        usage: "[global [today | month | all]]",
+        description: "Show what you and this chat used",
+        arguments: &[("global", "what everyone used, for admins only")],
+        translations: &[("nl", "Toon wat jij en deze chat verbruikt hebben")],
+        scopes: EVERYWHERE,
+    },
+    CommandInfo {
+        name: "allow",
+        usage: "[user <id> | chat <id>]",
+        description: "Give a user or chat access",
+        arguments: &[
+            ("user", "the id of a user"),
+            ("chat", "the id of a chat, groups have negative ids"),
+            ("without arguments", "this chat"),
+        ],
+        translations: &[("nl", "Geef een gebruiker of chat toegang")],
+        scopes: &[Scope::Admin],
+    },
+    CommandInfo {
+        name: "deny",
+        usage: "[user <id> | chat <id>]",
+        description: "Take away the access of a user or chat",
+        arguments: &[
+            ("user", "the id of a user"),
+            ("chat", "the id of a chat, groups have negative ids"),
+            ("without arguments", "this chat"),
+        ],
+        translations: &[("nl", "Ontzeg een gebruiker of chat de toegang")],
+        scopes: &[Scope::Admin],
+    },
+    CommandInfo {
+        name: "users",
+        usage: "",
+        description: "Show the admins and access lists",
+        arguments: &[],
+        translations: &[("nl", "Toon de beheerders en toegangslijsten")],
+        scopes: &[Scope::Admin],
+    },
+];
+
+/// A command split off the start of a message: `/name@bot args`
+#[derive(Debug, PartialEq)]
+pub struct CommandText<'a> {
+    /// The command word without the slash, lowercased
+    pub name: String,
+    /// The bot the command is addressed to in groups, e.g. `/frog@FrogAIBot`
+    pub bot: Option<&'a str>,
+    pub args: &'a str,
+}
 
-    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
-        tracing::debug!(?value, "value: ");
-        match value {
-            _ if value.starts_with("/startfrog") => Ok(Self::Start),
-            _ if value.starts_with("/list_models") => Ok(Self::ListModels),
-            _ if value.starts_with("/model") => Ok(Self::Model),
-            _ if value.starts_with("/frog") => Ok(Self::Frog(value.replace("/frog", ""))),
-            _ if value.starts_with("/change_model ") => {
-                Ok(Self::ChangeModel(value.replace("/change_model ", "")))
+impl<'a> CommandText<'a> {
+    /// Split the command off a message, `None` when the message isn't a command
+    /// Telegram marks commands with a `bot_command` entity, only one at the very start of the
+    /// message makes it a command. The text itself is only looked at without any entities
+    pub fn parse(text: &'a str, entities: Option<&[MessageEntity]>) -> Option<Self> {
+        let end = match entities {
+            Some(entities) => {
+                let entity = entities
+                    .iter()
+                    .find(|entity| entity.type_ == "bot_command" && entity.offset == 0)?;
+                utf16_to_byte(text, entity.length)?
             }
-            _ => Ok(Self::Unknown),
+            None => text.find(char::is_whitespace).unwrap_or(text.len()),
+        };
+        let (word, args) = text.split_at(end);
+        let word = word.strip_prefix('/')?;
+        let (name, bot) = match word.split_once('@') {
+            Some((name, bot)) => (name, Some(bot)),
+            None => (word, None),
+        };
+        Some(CommandText {
+            name: name.to_ascii_lowercase(),
+            bot,
+            args: args.trim(),
+        })
+    }
+
+    /// Whether the command is meant for the bot called `username`
+    /// Commands without `@bot` are meant for every bot in the chat
+    pub fn is_for(&self, username: Option<&str>) -> bool {
+        match (self.bot, username) {
+            (None, _) => true,
+            (Some(bot), Some(username)) => bot.eq_ignore_ascii_case(username),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// What `/usage` should report
+pub enum UsageAction {
+    Mine,
+    Global(Span),
+}
+
+impl TryFrom<&str> for UsageAction {
+    type Error = ArgError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut words = value.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => Ok(Self::Mine),
+            (Some("global"), None | Some("month"), None) => Ok(Self::Global(Span::Month)),
+            (Some("global"), Some("today"), None) => Ok(Self::Global(Span::Today)),
+            (Some("global"), Some("all"), None) => Ok(Self::Global(Span::AllTime)),
+            _ => Err(ArgError::Invalid {
+                value: value.to_string(),
+                expected: "a usage report",
+                example: "/usage, or /usage global today|month|all for admins",
+            }),
+        }
+    }
+}
+
+/// Who `/allow` and `/deny` are about
+pub enum TargetArg {
+    ThisChat,
+    Target(Target),
+}
+
+impl TryFrom<&str> for TargetArg {
+    type Error = ArgError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut words = value.split_whitespace();
+        match (words.next(), words.next().map(str::parse), words.next()) {
+            (None | Some("chat"), None, _) => Ok(Self::ThisChat),
+            (Some("user"), Some(Ok(id)), None) => Ok(Self::Target(Target::User(id))),
+            (Some("chat"), Some(Ok(id)), None) => Ok(Self::Target(Target::Chat(id))),
+            _ => Err(ArgError::Invalid {
+                value: value.to_string(),
+                expected: "a user or chat id",
+                example: "/allow user 123456, /deny chat -100123456, or no arguments for this chat",
+            }),
+        }
+    }
+}
+
+/// What `/params` should do with the sampling parameters of the chat
+pub enum ParamsAction {
+    Show,
+    Reset,
+    Set(String, String),
+}
+
+impl From<&str> for ParamsAction {
+    fn from(value: &str) -> Self {
+        match value.trim().split_once(char::is_whitespace) {
+            Some((name, value)) => Self::Set(name.to_string(), value.to_string()),
+            None => match value.trim() {
+                "" => Self::Show,
+                "reset" => Self::Reset,
+                name => Self::Set(name.to_string(), String::new()),
+            },
         }
     }
 }
 
+/// What `/persona` should do
+pub enum PersonaAction {
+    Show,
+    List,
+    Switch(String),
+}
+
+impl From<&str> for PersonaAction {
+    fn from(value: &str) -> Self {
+        match value.trim() {
+            "" => Self::Show,
+            "list" => Self::List,
+            name => Self::Switch(name.to_string()),
+        }
+    }
+}
+
+/// What `/system` should do with the system prompt of the chat
+pub enum SystemAction {
+    Show,
+    Reset,
+    Set(String),
+}
+
+impl From<&str> for SystemAction {
+    fn from(value: &str) -> Self {
+        match value.trim() {
+            "" | "show" => Self::Show,
+            "reset" => Self::Reset,
+            prompt => Self::Set(prompt.to_string()),
+        }
+    }
+}
+
+/// For commands that don't take arguments
+fn no_args(command: &'static str, args: &str, parsed: Command) -> Result<Command, ArgError> {
+    if args.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(ArgError::Unexpected { command })
+    }
+}
+
+/// For commands that can't do without their argument
+fn required(
+    command: &'static str,
+    args: &str,
+    what: &'static str,
+    example: &'static str,
+) -> Result<String, ArgError> {
+    if args.is_empty() {
+        Err(ArgError::Missing {
+            command,
+            what,
+            example,
+        })
+    } else {
+        Ok(args.to_string())
+    }
+}
+
+impl<'a> TryFrom<CommandText<'a>> for Command {
+    type Error = Error;
+
+    fn try_from(command: CommandText<'a>) -> Result<Self, Self::Error> {
+        tracing::debug!(?command, "parsing command");
+        let args = command.args;
+        Ok(match command.name.as_str() {
+            "startfrog" => no_args("startfrog", args, Self::Start)?,
+            "list_models" => no_args("list_models", args, Self::ListModels)?,
+            "model" => no_args("model", args, Self::Model)?,
+            "users" => no_args("users", args, Self::Users)?,
+            "frog" => Self::Frog(required(
+                "frog",
+                args,
+                "a question",
+                "/frog why is the sky blue?",
+            )?),
+            "change_model" => Self::ChangeModel(required(
+                "change_model",
+                args,
+                "a model",
+                "/change_model claude",
+            )?),
+            "params" => Self::Params(args.into()),
+            "persona" => Self::Persona(args.into()),
+            "system" => Self::System(args.into()),
+            "allow" => Self::Allow(args.try_into()?),
+            "deny" => Self::Deny(args.try_into()?),
+            "usage" => Self::Usage(args.try_into()?),
+            "help" if args.is_empty() => Self::Help(None),
+            "help" => Self::Help(Some(
+                CommandInfo::find(args.trim_start_matches('/')).ok_or_else(|| {
+                    ArgError::Invalid {
+                        value: args.to_string(),
+                        expected: "a command",
+                        example: "/help frog",
+                    }
+                })?,
+            )),
+            _ => Self::Unknown,
+        })
+    }
+}
+
 impl CommandTrait for Command {
-    async fn execute(&self, bot: &mut TgBot, chat_id: i64) -> Result<(), Error> {
-        let message = match self {
-            Self::Start => bot_messages::INITIAL_MESSAGE.to_string(),
-            Self::ListModels => bot_messages::MODEL_LIST.to_string(),
-            Self::Model => format!("i'm currently using: {}", bot.model()),
+    async fn execute(&self, bot: &TgBot, message: &Message) -> Result<(), Error> {
+        let chat_id = message.chat.get_id();
+        let user_id = message.from.id;
+        let to = Destination::reply_to(message);
+        let reply = match self {
+            Self::Start => match bot.persona(chat_id) {
+                Some(persona) => persona.greeting(),
+                None => bot_messages::INITIAL_MESSAGE.to_string(),
+            },
+            Self::ListModels => bot.models().list(),
+            Self::Model => format!("i'm currently using: {}", bot.model(chat_id, user_id)),
+            Self::Frog(query) if bot.cfg().stream => {
+                bot.check_limits(chat_id, user_id)?;
+                tracing::debug!("streaming answer to query");
+                let answer = bot
+                    .with_typing(&to, bot.answer_streaming(message, query))
+                    .await
+                    .inspect_err(|_| bot.refund_limits(user_id))?;
+                bot.record_answer(message, query, &answer);
+                return Ok(());
+            }
             Self::Frog(query) => {
+                bot.check_limits(chat_id, user_id)?;
                 tracing::debug!("answering query");
-                bot.call_open_router(query).await?.join("\n")
+                let answer = bot
+                    .with_typing(&to, bot.call_open_router(message, query))
+                    .await
+                    .inspect_err(|_| bot.refund_limits(user_id))?;
+                bot.record_answer(message, query, &answer);
+                return bot.send_answer(&to, &answer.text).await;
+            }
+            Self::ChangeModel(new_model) => match bot.change_model(chat_id, user_id, new_model) {
+                Ok(()) => format!("changed model to: {}", bot.model(chat_id, user_id)),
+                Err(Error::UnknownModel(name)) => format!(
+                    "i don't know the model '{}', type /list_models to see the available models",
+                    name
+                ),
+                Err(e) => return Err(e),
+            },
+            Self::System(SystemAction::Show) => {
+                format!("the system prompt is: {}", bot.system_prompt(chat_id))
+            }
+            Self::System(SystemAction::Reset) => {
+                bot.set_system_prompt(chat_id, None)?;
+                bot_messages::SYSTEM_PROMPT_RESET.to_string()
+            }
+            Self::System(SystemAction::Set(prompt)) => {
+                bot.set_system_prompt(chat_id, Some(prompt.clone()))?;
+                bot_messages::SYSTEM_PROMPT_SET.to_string()
+            }
+            Self::Persona(PersonaAction::Show) => match bot.persona(chat_id) {
+                Some(persona) => format!("i'm currently: {}", persona.name),
+                None => bot_messages::PERSONA_DEFAULT.to_string(),
+            },
+            Self::Persona(PersonaAction::List) => {
+                let active = bot.persona(chat_id).map(|persona| persona.name);
+                bot.personas().list(active.as_deref())
+            }
+            Self::Persona(PersonaAction::Switch(name)) => match bot.set_persona(chat_id, name) {
+                Ok(persona) => persona.greeting(),
+                Err(Error::UnknownPersona(name)) => format!(
+                    "i don't know the persona '{}', type /persona list to see the available personas",
+                    name
+                ),
+                Err(e) => return Err(e),
+            },
+            Self::Params(ParamsAction::Show) => bot.sampling_params(chat_id).describe(),
+            Self::Params(ParamsAction::Reset) => {
+                bot.update_params(chat_id, |params| {
+                    *params = Default::default();
+                    Ok(())
+                })?;
+                bot_messages::PARAMS_RESET.to_string()
             }
-            Self::ChangeModel(new_model) => {
-                bot.change_model(new_model);
-                format!("changed model to: {}", bot.model())
+            Self::Params(ParamsAction::Set(name, value)) => {
+                match bot.update_params(chat_id, |params| params.set(name, value)) {
+                    Ok(()) => bot.sampling_params(chat_id).describe(),
+                    Err(Error::InvalidParam(reason)) => reason,
+                    Err(e) => return Err(e),
+                }
             }
+            Self::Allow(_) | Self::Deny(_) | Self::Users if !bot.is_admin(user_id) => {
+                bot_messages::ADMIN_ONLY.to_string()
+            }
+            Self::Allow(target) | Self::Deny(target) => {
+                let target = match target {
+                    TargetArg::Target(target) => *target,
+                    TargetArg::ThisChat => Target::Chat(chat_id),
+                };
+                if matches!(self, Self::Allow(_)) {
+                    bot.allow(target)?;
+                } else {
+                    bot.deny(target)?;
+                }
+                bot.access_lists()
+            }
+            Self::Users => bot.access_lists(),
+            Self::Help(None) => help(
+                message.from.language_code.as_deref(),
+                bot.is_admin(user_id),
+            ),
+            Self::Help(Some(info)) => info.help(message.from.language_code.as_deref()),
+            Self::Usage(UsageAction::Mine) => {
+                bot.describe_usage(chat_id, user_id, message.chat.is_private())
+            }
+            Self::Usage(UsageAction::Global(_)) if !bot.is_admin(user_id) => {
+                bot_messages::ADMIN_ONLY.to_string()
+            }
+            Self::Usage(UsageAction::Global(span)) => bot.usage_report(*span),
             Self::Unknown => {
                 tracing::debug!("unknown command");
                 return Ok(());
             }
         };
 
-        bot.send_message(chat_id, &message).await
+        bot.send_reply(&to, &reply).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_entity(length: i64) -> Vec<MessageEntity> {
+        vec![MessageEntity {
+            offset: 0,
+            length,
+            type_: "bot_command".to_string(),
+        }]
+    }
+
+    fn parse(text: &str) -> Result<Command, Error> {
+        let entities = command_entity(text.split_whitespace().next().unwrap().len() as i64);
+        Command::try_from(CommandText::parse(text, Some(&entities)).unwrap())
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert!(matches!(parse("/modelfoo"), Ok(Command::Unknown)));
+        assert!(matches!(parse("/frogger what"), Ok(Command::Unknown)));
+        assert!(matches!(parse("/frog  why? "), Ok(Command::Frog(q)) if q == "why?"));
+        assert!(matches!(
+            parse("/change_model"),
+            Err(Error::Args(ArgError::Missing {
+                command: "change_model",
+                ..
+            }))
+        ));
+        assert!(matches!(
+            parse("/model gpt"),
+            Err(Error::Args(ArgError::Unexpected { command: "model" }))
+        ));
+        assert!(matches!(
+            parse("/allow user frog"),
+            Err(Error::Args(ArgError::Invalid { .. }))
+        ));
+
+        let text = "/Frog@FrogAIBot 🐸 hi";
+        let command = CommandText::parse(text, Some(&command_entity(15))).unwrap();
+        assert_eq!(command.name, "frog");
+        assert_eq!(command.args, "🐸 hi");
+        assert!(command.is_for(Some("frogaibot")));
+        assert!(!command.is_for(Some("OtherBot")));
+
+        // Only a command entity at the start of the message makes it a command
+        let mention = vec![MessageEntity {
+            offset: 3,
+            length: 5,
+            type_: "bot_command".to_string(),
+        }];
+        assert_eq!(CommandText::parse("hi /frog", Some(&mention)), None);
+        assert_eq!(CommandText::parse("hi there", Some(&[])), None);
+    }
+
+    #[test]
+    fn test_registered_commands_are_parsed() {
+        for info in COMMANDS {
+            let command = CommandText {
+                name: info.name.to_string(),
+                bot: None,
+                args: "",
+            };
+            assert!(
+                !matches!(Command::try_from(command), Ok(Command::Unknown)),
+                "/{} is registered but not parsed",
+                info.name
+            );
+        }
+
+        let everyone = help(None, false);
+        assert!(everyone.contains("/change_model <model> - Switch to another model"));
+        assert!(!everyone.contains("/allow"));
+        assert!(help(Some("nl"), true).contains("/allow [user <id> | chat <id>] - Geef"));
+        let frog = CommandInfo::find("frog").unwrap().help(None);
+        assert!(frog.starts_with("/frog <question>\nAsk the model a question\n- question:"));
+        assert!(CommandInfo::find("users")
+            .unwrap()
+            .help(None)
+            .ends_with("only admins can use this command."));
     }
 }
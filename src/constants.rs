@@ -1 +1,10 @@
 pub const OPEN_ROUTER_COMPLETIONS_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
+pub const OPEN_ROUTER_MODELS_URL: &str = "https://openrouter.ai/api/v1/models";
+/// The update types the bot handles, Telegram doesn't send us any others
+pub const ALLOWED_UPDATES: &[&str] = &["message"];
+/// Maximum length of the text of a single Telegram message
+pub const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+/// How often a Telegram call is retried when Telegram says we are sending too many requests
+pub const TELEGRAM_MAX_RETRIES: u32 = 3;
+/// How often the typing indicator is sent again, Telegram shows it for at most 5 seconds
+pub const TYPING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(4);
@@ -0,0 +1,68 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Semaphore;
+
+use crate::messages::telegram::Message;
+use crate::telegram_bot::TgBot;
+
+/// Dispatcher
+/// Hands incoming messages to concurrent tasks so a slow completion in one chat doesn't
+/// block the others. Messages of the same chat are queued and handled one at a time, in
+/// the order they arrived. At most `max_concurrent` messages are handled at once
+#[derive(Clone)]
+pub struct Dispatcher {
+    bot: Arc<TgBot>,
+    /// Pending messages per chat, a chat has an entry exactly when a worker is running for it
+    queues: Arc<Mutex<HashMap<i64, VecDeque<Message>>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl Dispatcher {
+    pub fn new(bot: Arc<TgBot>, max_concurrent: usize) -> Self {
+        Dispatcher {
+            bot,
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+        }
+    }
+
+    /// Queue a message behind the other messages of its chat
+    /// Starts a worker for the chat if none is running
+    pub fn dispatch(&self, message: Message) {
+        let chat_id = message.chat.get_id();
+        let mut queues = self.queues.lock().expect("dispatcher queues poisoned");
+        match queues.get_mut(&chat_id) {
+            Some(queue) => queue.push_back(message),
+            None => {
+                queues.insert(chat_id, VecDeque::from([message]));
+                tokio::spawn(self.clone().work(chat_id));
+            }
+        }
+    }
+
+    /// Handle the messages of a chat until its queue is empty
+    async fn work(self, chat_id: i64) {
+        loop {
+            let message = {
+                let mut queues = self.queues.lock().expect("dispatcher queues poisoned");
+                match queues.get_mut(&chat_id).and_then(VecDeque::pop_front) {
+                    Some(message) => message,
+                    None => {
+                        queues.remove(&chat_id);
+                        return;
+                    }
+                }
+            };
+
+            let _permit = self
+                .permits
+                .acquire()
+                .await
+                .expect("dispatcher semaphore closed");
+            if let Err(e) = self.bot.handle_update(&message).await {
+                tracing::error!(?e, chat_id, "Failed to handle update");
+            }
+        }
+    }
+}
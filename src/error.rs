@@ -1,3 +1,10 @@
+use std::time::Duration;
+
+use crate::limits::Period;
+use crate::messages::bot_messages;
+use crate::messages::{openrouter, telegram};
+use crate::utils::format_duration;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("HTTP error: {0}")]
@@ -5,7 +12,226 @@ pub enum Error {
 
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Telegram error {error_code}: {description}")]
+    Telegram {
+        error_code: i64,
+        description: String,
+        /// Seconds to wait before retrying, set when we hit a rate limit
+        retry_after: Option<u64>,
+    },
+
+    #[error("OpenRouter rate limit hit: {0}")]
+    OpenRouterRateLimited(String),
+
+    #[error("OpenRouter credits exhausted: {0}")]
+    OpenRouterQuota(String),
+
+    #[error("OpenRouter flagged the input: {message} ({reasons:?})")]
+    OpenRouterModeration {
+        message: String,
+        reasons: Vec<String>,
+    },
+
+    #[error("OpenRouter provider unavailable: {0}")]
+    OpenRouterProviderDown(String),
+
+    #[error("OpenRouter request timed out")]
+    OpenRouterTimeout,
+
+    /// `code` is the HTTP status, 0 when no answer came back at all
+    #[error("OpenRouter error {code}: {message}")]
+    OpenRouter { code: u16, message: String },
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("Unknown model: {0}")]
+    UnknownModel(String),
+
+    #[error("Unknown persona: {0}")]
+    UnknownPersona(String),
+
+    #[error("Invalid parameter: {0}")]
+    InvalidParam(String),
+
+    #[error("Invalid command arguments: {0}")]
+    Args(#[from] ArgError),
+
+    #[error("Rate limited, retry in {0:?}")]
+    RateLimited(Duration),
+
+    #[error("{period} quota exceeded, resets in {resets_in:?}")]
+    QuotaExceeded { period: Period, resets_in: Duration },
     // Er zijn twee soorten errors:
     // 1. Errors die je zelf definieert, zoals hierboven Generic
     // 2. Errors die je van andere libraries overneemt, zoals Http en Json
 }
+
+/// Why the arguments of a command couldn't be used, the message is shown to the user
+#[derive(thiserror::Error, Debug)]
+pub enum ArgError {
+    #[error("/{command} needs {what}, e.g. {example}")]
+    Missing {
+        command: &'static str,
+        what: &'static str,
+        example: &'static str,
+    },
+
+    #[error("/{command} doesn't take any arguments")]
+    Unexpected { command: &'static str },
+
+    #[error("'{value}' isn't {expected}, e.g. {example}")]
+    Invalid {
+        value: String,
+        expected: &'static str,
+        example: &'static str,
+    },
+}
+
+impl From<telegram::ErrorResponse> for Error {
+    fn from(response: telegram::ErrorResponse) -> Self {
+        Error::Telegram {
+            error_code: response.error_code,
+            description: response.description,
+            retry_after: response.parameters.and_then(|p| p.retry_after),
+        }
+    }
+}
+
+/// Sort OpenRouter errors by what the user can do about them
+impl From<openrouter::ErrorResponse> for Error {
+    fn from(response: openrouter::ErrorResponse) -> Self {
+        let error = response.error;
+        match error.code {
+            402 => Error::OpenRouterQuota(error.message),
+            403 => Error::OpenRouterModeration {
+                reasons: error
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata["reasons"].as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|reason| reason.as_str().map(str::to_string))
+                    .collect(),
+                message: error.message,
+            },
+            408 => Error::OpenRouterTimeout,
+            429 => Error::OpenRouterRateLimited(error.message),
+            502 | 503 => Error::OpenRouterProviderDown(error.message),
+            code => Error::OpenRouter {
+                code,
+                message: error.message,
+            },
+        }
+    }
+}
+
+impl Error {
+    /// Whether the error was reported by OpenRouter, rather than happening on our side
+    pub fn is_open_router(&self) -> bool {
+        matches!(
+            self,
+            Error::OpenRouterRateLimited(_)
+                | Error::OpenRouterQuota(_)
+                | Error::OpenRouterModeration { .. }
+                | Error::OpenRouterProviderDown(_)
+                | Error::OpenRouterTimeout
+                | Error::OpenRouter { .. }
+        )
+    }
+
+    /// Whether Telegram refused a request as invalid, e.g. HTML it can't parse or a text that
+    /// is too long
+    pub fn is_bad_request(&self) -> bool {
+        matches!(
+            self,
+            Error::Telegram {
+                error_code: 400,
+                ..
+            }
+        )
+    }
+
+    /// Whether Telegram refused an edit because the message already looks like that
+    pub fn is_not_modified(&self) -> bool {
+        matches!(
+            self,
+            Error::Telegram { error_code: 400, description, .. }
+                if description.contains("message is not modified")
+        )
+    }
+
+    /// What to tell the user when answering their question failed
+    /// `None` for errors that aren't worth bothering the user with
+    pub fn user_message(&self) -> Option<String> {
+        Some(match self {
+            Error::OpenRouterRateLimited(_) => bot_messages::ERROR_RATE_LIMITED.to_string(),
+            Error::OpenRouterQuota(_) => bot_messages::ERROR_QUOTA.to_string(),
+            Error::OpenRouterModeration { reasons, .. } if !reasons.is_empty() => format!(
+                "{} ({})",
+                bot_messages::ERROR_MODERATION,
+                reasons.join(", ")
+            ),
+            Error::OpenRouterModeration { .. } => bot_messages::ERROR_MODERATION.to_string(),
+            Error::OpenRouterProviderDown(_) => bot_messages::ERROR_PROVIDER_DOWN.to_string(),
+            Error::OpenRouterTimeout => bot_messages::ERROR_TIMEOUT.to_string(),
+            Error::OpenRouter { code: 400, message } => {
+                format!("{} {}", bot_messages::ERROR_BAD_REQUEST, message)
+            }
+            Error::OpenRouter { .. } => bot_messages::ERROR_OPEN_ROUTER.to_string(),
+            Error::Args(e) => e.to_string(),
+            Error::RateLimited(wait) => {
+                format!("{} {}.", bot_messages::RATE_LIMITED, format_duration(*wait))
+            }
+            Error::QuotaExceeded { period, resets_in } => format!(
+                "you've used up your {} quota, it resets in {} ({}).",
+                period,
+                format_duration(*resets_in),
+                match period {
+                    Period::Day => "at midnight UTC",
+                    Period::Month => "on the 1st of the month, UTC",
+                }
+            ),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_router_errors() {
+        let moderation: openrouter::ErrorResponse = serde_json::from_str(
+            r#"{
+                "error": {
+                    "code": 403,
+                    "message": "Input was flagged",
+                    "metadata": { "reasons": ["violence"], "flagged_input": "..." }
+                }
+            }"#,
+        )
+        .unwrap();
+        let error = Error::from(moderation);
+        assert!(error.is_open_router());
+        assert!(
+            matches!(&error, Error::OpenRouterModeration { reasons, .. } if reasons == &["violence"])
+        );
+        assert!(error.user_message().unwrap().contains("violence"));
+
+        let quota: openrouter::ErrorResponse = serde_json::from_str(
+            r#"{ "error": { "code": 402, "message": "Insufficient credits" } }"#,
+        )
+        .unwrap();
+        assert!(matches!(Error::from(quota), Error::OpenRouterQuota(_)));
+
+        let config = Error::Config("missing".to_string());
+        assert!(!config.is_open_router());
+        assert!(config.user_message().is_none());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::Error;
+use crate::messages::openrouter::Message;
+use crate::messages::telegram;
+use crate::storage::{MemoryStorage, Storage};
+
+/// Key the history is stored under
+const STORAGE_KEY: &str = "history";
+
+/// Rough number of characters per token, used to estimate the size of a turn
+/// without pulling in a tokenizer. Good enough to keep requests within budget.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// A single `/frog` exchange: the question that was asked and the answer we gave
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+}
+
+impl Turn {
+    /// Estimated token count of both sides of the exchange
+    fn tokens(&self) -> usize {
+        estimate_tokens(&self.question) + estimate_tokens(&self.answer)
+    }
+}
+
+/// Estimated token count of a text
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// What history is kept for: a chat, or a single topic of a forum supergroup
+/// Stored as `chat` or `chat:topic`, so history stored before topics were kept still loads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Conversation {
+    pub chat_id: i64,
+    pub topic_id: Option<i64>,
+}
+
+impl Conversation {
+    /// The conversation a message is part of
+    pub fn of(message: &telegram::Message) -> Self {
+        Conversation {
+            chat_id: message.chat.get_id(),
+            topic_id: message.topic_id(),
+        }
+    }
+}
+
+impl Serialize for Conversation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.topic_id {
+            Some(topic_id) => {
+                serializer.collect_str(&format_args!("{}:{}", self.chat_id, topic_id))
+            }
+            None => serializer.collect_str(&self.chat_id),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Conversation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        let parse = |id: &str| id.parse::<i64>().map_err(serde::de::Error::custom);
+        Ok(match key.split_once(':') {
+            Some((chat_id, topic_id)) => Conversation {
+                chat_id: parse(chat_id)?,
+                topic_id: Some(parse(topic_id)?),
+            },
+            None => Conversation {
+                chat_id: parse(&key)?,
+                topic_id: None,
+            },
+        })
+    }
+}
+
+/// Per-conversation history
+/// Keeps the most recent turns of every chat, or topic, so they can be replayed to the model
+/// When either the turn limit or the token budget is exceeded the oldest turns are dropped
+#[derive(Debug)]
+pub struct History {
+    chats: HashMap<Conversation, VecDeque<Turn>>,
+    storage: Arc<dyn Storage>,
+    max_turns: usize,
+    max_tokens: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(0, 0)
+    }
+}
+
+impl History {
+    /// A history that only lives in memory
+    pub fn new(max_turns: usize, max_tokens: usize) -> Self {
+        History {
+            chats: HashMap::new(),
+            storage: Arc::new(MemoryStorage::default()),
+            max_turns,
+            max_tokens,
+        }
+    }
+
+    /// Load the history persisted in `storage`
+    pub fn load(
+        storage: Arc<dyn Storage>,
+        max_turns: usize,
+        max_tokens: usize,
+    ) -> Result<Self, Error> {
+        Ok(History {
+            chats: storage.load(STORAGE_KEY)?.unwrap_or_default(),
+            storage,
+            max_turns,
+            max_tokens,
+        })
+    }
+
+    /// Replay the history of a conversation as OpenRouter messages, oldest first
+    pub fn messages(&self, conversation: Conversation) -> Vec<Message> {
+        self.chats
+            .get(&conversation)
+            .into_iter()
+            .flatten()
+            .flat_map(|turn| {
+                [
+                    Message {
+                        role: "user".to_string(),
+                        content: turn.question.clone(),
+                    },
+                    Message {
+                        role: "assistant".to_string(),
+                        content: turn.answer.clone(),
+                    },
+                ]
+            })
+            .collect()
+    }
+
+    /// Append an exchange to a conversation, trim it back within limits and persist it
+    pub fn push(
+        &mut self,
+        conversation: Conversation,
+        question: &str,
+        answer: &str,
+    ) -> Result<(), Error> {
+        let turns = self.chats.entry(conversation).or_default();
+        turns.push_back(Turn {
+            question: question.to_string(),
+            answer: answer.to_string(),
+        });
+
+        while turns.len() > self.max_turns {
+            turns.pop_front();
+        }
+        while turns.iter().map(Turn::tokens).sum::<usize>() > self.max_tokens {
+            turns.pop_front();
+        }
+        if turns.is_empty() {
+            self.chats.remove(&conversation);
+        }
+        self.storage.store(STORAGE_KEY, &self.chats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(chat_id: i64) -> Conversation {
+        Conversation {
+            chat_id,
+            topic_id: None,
+        }
+    }
+
+    #[test]
+    fn test_history_trims_oldest_turns() {
+        let mut history = History::new(2, 1000);
+        history.push(chat(1), "first", "a").unwrap();
+        history.push(chat(1), "second", "b").unwrap();
+        history.push(chat(1), "third", "c").unwrap();
+        history.push(chat(2), "other chat", "d").unwrap();
+
+        let messages = history.messages(chat(1));
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[0].content, "second");
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(messages[3].content, "c");
+        assert_eq!(history.messages(chat(2)).len(), 2);
+
+        // Topics of a forum have their own history, stored next to that of the whole chat
+        let topic = Conversation {
+            chat_id: 1,
+            topic_id: Some(7),
+        };
+        history.push(topic, "in a topic", "e").unwrap();
+        assert_eq!(history.messages(topic).len(), 2);
+        assert_eq!(history.messages(chat(1)).len(), 4);
+        let stored: HashMap<Conversation, VecDeque<Turn>> =
+            serde_json::from_value(serde_json::to_value(&history.chats).unwrap()).unwrap();
+        assert_eq!(stored[&topic][0].question, "in a topic");
+
+        // 40 chars is ~10 tokens, so only the newest turn fits in a budget of 12
+        let mut history = History::new(10, 12);
+        history
+            .push(chat(1), &"x".repeat(20), &"y".repeat(20))
+            .unwrap();
+        history
+            .push(chat(1), &"z".repeat(20), &"w".repeat(20))
+            .unwrap();
+        assert_eq!(history.messages(chat(1)).len(), 2);
+        assert_eq!(history.messages(chat(1))[0].content, "z".repeat(20));
+
+        // A single turn over budget is not kept at all
+        let mut history = History::new(10, 1);
+        history.push(chat(1), "too long", "for the budget").unwrap();
+        assert!(history.messages(chat(1)).is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::storage::{MemoryStorage, Storage};
+use crate::utils::{days_from_date, days_since_epoch, month_of, SECS_PER_DAY};
+
+/// Key the quota usage is stored under
+const STORAGE_KEY: &str = "quotas";
+
+/// A token bucket holding up to `capacity` requests, refilled at a steady rate
+/// Allows short bursts while keeping the average rate in check
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing `per_minute` requests a minute
+    pub fn new(per_minute: u32, now: Instant) -> Self {
+        let capacity = f64::from(per_minute.max(1));
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            per_second: capacity / 60.0,
+            updated: now,
+        }
+    }
+
+    /// How long until a request can be taken, zero when it can be taken right away
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.per_second)
+        }
+    }
+
+    pub fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// Whether the bucket has refilled completely, it's no different from a new one then
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.per_second >= self.capacity
+    }
+}
+
+/// Rate limits `/frog` per user and per chat, in requests per minute
+/// Only lives in memory, a restart gives everyone a full bucket again. Full buckets are
+/// dropped, so only users and chats that asked something recently are kept
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    user_limit: Option<u32>,
+    chat_limit: Option<u32>,
+    users: HashMap<i64, TokenBucket>,
+    chats: HashMap<i64, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(user_limit: Option<u32>, chat_limit: Option<u32>) -> Self {
+        RateLimiter {
+            user_limit,
+            chat_limit,
+            ..Default::default()
+        }
+    }
+
+    /// Take a request from the buckets of both the user and the chat
+    /// When either is empty nothing is taken, and the time until both have a request again
+    /// is returned instead
+    pub fn acquire(&mut self, chat_id: i64, user_id: i64, now: Instant) -> Result<(), Duration> {
+        let RateLimiter {
+            user_limit,
+            chat_limit,
+            users,
+            chats,
+        } = self;
+        users.retain(|_, bucket| !bucket.is_full(now));
+        chats.retain(|_, bucket| !bucket.is_full(now));
+        let mut buckets: Vec<&mut TokenBucket> = user_limit
+            .map(|limit| {
+                users
+                    .entry(user_id)
+                    .or_insert_with(|| TokenBucket::new(limit, now))
+            })
+            .into_iter()
+            .chain(chat_limit.map(|limit| {
+                chats
+                    .entry(chat_id)
+                    .or_insert_with(|| TokenBucket::new(limit, now))
+            }))
+            .collect();
+
+        let wait = buckets
+            .iter_mut()
+            .map(|bucket| bucket.wait(now))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        buckets.into_iter().for_each(TokenBucket::take);
+        Ok(())
+    }
+}
+
+/// How much a single user may use the bot, unset quotas are unlimited
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Quotas {
+    pub daily_requests: Option<u64>,
+    pub monthly_requests: Option<u64>,
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+}
+
+impl Quotas {
+    fn has_monthly(&self) -> bool {
+        self.monthly_requests.is_some() || self.monthly_tokens.is_some()
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.daily_requests.is_none()
+            && self.monthly_requests.is_none()
+            && self.daily_tokens.is_none()
+            && self.monthly_tokens.is_none()
+    }
+}
+
+/// The period a quota covers, both reset at midnight UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Period::Day => write!(f, "daily"),
+            Period::Month => write!(f, "monthly"),
+        }
+    }
+}
+
+/// What a user used in the current day and month
+/// `day` is counted in days since the unix epoch, `month` as `year * 12 + month - 1`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct QuotaUsage {
+    day: i64,
+    day_requests: u64,
+    day_tokens: u64,
+    month: i64,
+    month_requests: u64,
+    month_tokens: u64,
+}
+
+impl QuotaUsage {
+    /// Whether everything counted has reset by `day`, only the month matters when there are
+    /// monthly quotas
+    fn is_past(&self, day: i64, monthly: bool) -> bool {
+        if monthly {
+            self.month != month_of(day)
+        } else {
+            self.day != day
+        }
+    }
+
+    /// Start counting from zero when a new day or month has begun
+    fn roll(&mut self, now: SystemTime) {
+        let day = days_since_epoch(now);
+        let month = month_of(day);
+        if self.day != day {
+            self.day = day;
+            self.day_requests = 0;
+            self.day_tokens = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.month_requests = 0;
+            self.month_tokens = 0;
+        }
+    }
+}
+
+/// Time left until a period is over
+fn resets_in(period: Period, now: SystemTime) -> Duration {
+    let today = days_since_epoch(now);
+    let next = match period {
+        Period::Day => today + 1,
+        Period::Month => {
+            let month = month_of(today) + 1;
+            days_from_date(month.div_euclid(12), month.rem_euclid(12) as u32 + 1, 1)
+        }
+    };
+    let end = SystemTime::UNIX_EPOCH + Duration::from_secs(next as u64 * SECS_PER_DAY);
+    end.duration_since(now).unwrap_or_default()
+}
+
+/// Keeps track of the quotas of every user, persisted so a restart doesn't reset them
+/// Users whose usage has reset are dropped, they start from zero either way
+#[derive(Debug)]
+pub struct QuotaTracker {
+    quotas: Quotas,
+    users: HashMap<i64, QuotaUsage>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for QuotaTracker {
+    fn default() -> Self {
+        QuotaTracker {
+            quotas: Quotas::default(),
+            users: HashMap::new(),
+            storage: Arc::new(MemoryStorage::default()),
+        }
+    }
+}
+
+impl QuotaTracker {
+    pub fn load(storage: Arc<dyn Storage>, quotas: Quotas) -> Result<Self, Error> {
+        Ok(QuotaTracker {
+            quotas,
+            users: storage.load(STORAGE_KEY)?.unwrap_or_default(),
+            storage,
+        })
+    }
+
+    /// Count a request of a user, refused when one of their quotas is used up
+    pub fn start_request(&mut self, user_id: i64, now: SystemTime) -> Result<(), Error> {
+        if self.quotas.is_unlimited() {
+            return Ok(());
+        }
+        let quotas = self.quotas;
+        let today = days_since_epoch(now);
+        self.users
+            .retain(|_, usage| !usage.is_past(today, quotas.has_monthly()));
+        let usage = self.users.entry(user_id).or_default();
+        usage.roll(now);
+
+        let exceeded = |used: u64, quota: Option<u64>| quota.is_some_and(|quota| used >= quota);
+        // The month is checked first, its quota keeps the user waiting longest
+        let period = if exceeded(usage.month_requests, quotas.monthly_requests)
+            || exceeded(usage.month_tokens, quotas.monthly_tokens)
+        {
+            Some(Period::Month)
+        } else if exceeded(usage.day_requests, quotas.daily_requests)
+            || exceeded(usage.day_tokens, quotas.daily_tokens)
+        {
+            Some(Period::Day)
+        } else {
+            None
+        };
+        if let Some(period) = period {
+            return Err(Error::QuotaExceeded {
+                period,
+                resets_in: resets_in(period, now),
+            });
+        }
+
+        usage.day_requests += 1;
+        usage.month_requests += 1;
+        self.save()
+    }
+
+    /// Give back a request that failed before it was answered, it shouldn't count
+    pub fn refund_request(&mut self, user_id: i64, now: SystemTime) -> Result<(), Error> {
+        let Some(usage) = self.users.get_mut(&user_id) else {
+            return Ok(());
+        };
+        // What was counted on an earlier day or month has already been reset
+        usage.roll(now);
+        usage.day_requests = usage.day_requests.saturating_sub(1);
+        usage.month_requests = usage.month_requests.saturating_sub(1);
+        self.save()
+    }
+
+    /// Count the tokens a request of a user used
+    pub fn add_tokens(&mut self, user_id: i64, tokens: u64, now: SystemTime) -> Result<(), Error> {
+        if self.quotas.is_unlimited() {
+            return Ok(());
+        }
+        let usage = self.users.entry(user_id).or_default();
+        usage.roll(now);
+        usage.day_tokens += tokens;
+        usage.month_tokens += tokens;
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        self.storage.store(STORAGE_KEY, &self.users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limits_and_quotas() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(Some(2), Some(3));
+        assert!(limiter.acquire(1, 10, start).is_ok());
+        assert!(limiter.acquire(1, 10, start).is_ok());
+        // The user's bucket is empty, one request comes back every 30 seconds
+        let wait = limiter.acquire(1, 10, start).unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        // The chat's bucket has one left, which the refused request didn't take
+        assert!(limiter.acquire(1, 20, start).is_ok());
+        assert!(limiter.acquire(1, 30, start).is_err());
+        assert!(limiter
+            .acquire(1, 10, start + Duration::from_secs(30))
+            .is_ok());
+        // Once the buckets have refilled they are dropped
+        assert!(limiter
+            .acquire(2, 40, start + Duration::from_secs(120))
+            .is_ok());
+        assert_eq!((limiter.users.len(), limiter.chats.len()), (1, 1));
+
+        let quotas = Quotas {
+            daily_requests: Some(2),
+            monthly_tokens: Some(100),
+            ..Default::default()
+        };
+        let mut tracker = QuotaTracker::load(Arc::new(MemoryStorage::default()), quotas).unwrap();
+        // 2024-01-31 23:00 UTC
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(19_753 * SECS_PER_DAY + 23 * 3600);
+        tracker.start_request(1, now).unwrap();
+        tracker.start_request(1, now).unwrap();
+        // A failed request is given back
+        tracker.refund_request(1, now).unwrap();
+        tracker.start_request(1, now).unwrap();
+        assert!(matches!(
+            tracker.start_request(1, now),
+            Err(Error::QuotaExceeded { period: Period::Day, resets_in })
+                if resets_in == Duration::from_secs(3600)
+        ));
+
+        // A new day, and a new month, starts the next hour
+        let next_day = now + Duration::from_secs(3600);
+        tracker.start_request(1, next_day).unwrap();
+        tracker.add_tokens(1, 100, next_day).unwrap();
+        assert!(matches!(
+            tracker.start_request(1, next_day),
+            Err(Error::QuotaExceeded { period: Period::Month, resets_in })
+                if resets_in == Duration::from_secs(29 * SECS_PER_DAY)
+        ));
+
+        // Users from an earlier month are dropped
+        tracker.start_request(2, next_day).unwrap();
+        let next_month = next_day + Duration::from_secs(29 * SECS_PER_DAY);
+        tracker.start_request(2, next_month).unwrap();
+        assert_eq!(tracker.users.len(), 1);
+    }
+}
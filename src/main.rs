@@ -1,18 +1,36 @@
 use crate::telegram_bot::TgBot;
 use clap::Parser;
 
+mod access;
+mod chat_settings;
+mod chunking;
+mod command_menu;
 mod commands;
 mod constants;
+mod dispatcher;
 mod error;
+mod history;
+mod limits;
+mod markdown;
 mod messages;
 mod model;
 mod open_router;
+mod params;
+mod persona;
+mod storage;
+mod streaming;
 mod telegram_bot;
+mod typing;
+mod usage;
 mod utils;
+mod webhook;
 
 use dotenvy::dotenv;
 use error::Error;
+use messages::bot_messages;
 use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(clap::Parser)]
 #[command(
@@ -25,15 +43,225 @@ use std::env;
                   a simple interface for chatting with AI directly in Telegram."
 )]
 struct Args {
-    /// Polling interval in milliseconds
-    /// NOTE: This was hardcoded before, now it's configurable but the default value is 5000
-    /// which was the previously hardcoded value.
+    /// How updates are received from Telegram
     #[clap(
         long,
-        default_value = "5000",
-        help = "Set the bot's polling interval in milliseconds"
+        value_enum,
+        default_value = "polling",
+        help = "Receive updates by polling or through a webhook"
     )]
-    polling_interval: u64,
+    mode: Mode,
+
+    /// Public url Telegram posts updates to, required in webhook mode
+    #[clap(long, help = "Set the public url of the webhook")]
+    webhook_url: Option<String>,
+
+    /// Address the webhook server listens on
+    #[clap(
+        long,
+        default_value = "0.0.0.0:8443",
+        help = "Set the address the webhook server listens on"
+    )]
+    webhook_listen: SocketAddr,
+
+    /// How long a single `getUpdates` call waits for new updates, in seconds
+    /// NOTE: This replaces the fixed polling interval, Telegram holds the request open
+    /// until an update arrives or the timeout passes, so updates are handled right away.
+    #[clap(
+        long,
+        default_value = "30",
+        value_parser = clap::value_parser!(u64).range(1..=50),
+        help = "Set the long polling timeout in seconds (1-50)"
+    )]
+    polling_timeout: u64,
+
+    /// Maximum number of updates fetched per `getUpdates` call
+    #[clap(
+        long,
+        default_value = "100",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "Set the maximum number of updates fetched at once (1-100)"
+    )]
+    polling_limit: u8,
+
+    /// Delay before retrying after `getUpdates` failed, doubled on every consecutive failure
+    #[clap(
+        long,
+        default_value = "1000",
+        help = "Set the initial retry delay after a failed poll in milliseconds"
+    )]
+    backoff_base: u64,
+
+    /// Upper bound for the retry delay after consecutive failed polls
+    #[clap(
+        long,
+        default_value = "60000",
+        help = "Set the maximum retry delay after failed polls in milliseconds"
+    )]
+    backoff_max: u64,
+
+    /// Maximum number of `/frog` exchanges remembered per chat
+    #[clap(
+        long,
+        default_value = "10",
+        help = "Set the maximum number of turns remembered per chat"
+    )]
+    history_max_turns: usize,
+
+    /// Rough token budget for the remembered history of a chat, oldest turns are dropped first
+    #[clap(
+        long,
+        default_value = "2000",
+        help = "Set the approximate token budget of the history replayed per chat"
+    )]
+    history_max_tokens: usize,
+
+    /// Model used in chats that haven't picked one with `/change_model`
+    #[clap(
+        long,
+        default_value = "openai/gpt-4o",
+        help = "Set the default model for chats without a selected model"
+    )]
+    default_model: String,
+
+    /// Instructions sent to the model before every conversation, chats can replace them with
+    /// `/system`
+    #[clap(long, help = "Set the default system prompt")]
+    system_prompt: Option<String>,
+
+    /// Default sampling parameters as `name=value`, e.g. `--param temperature=0.7`
+    /// Chats can override them with `/params`
+    #[clap(
+        long = "param",
+        help = "Set a default sampling parameter, as name=value"
+    )]
+    params: Vec<String>,
+
+    /// Let every user in a chat pick their own model instead of sharing one per chat
+    #[clap(long, help = "Scope model selection per user within a chat")]
+    per_user_model: bool,
+
+    /// File the update offset, per-chat settings and history are persisted in
+    #[clap(
+        long,
+        default_value = "bot_state.json",
+        help = "Set the file the bot's state is stored in"
+    )]
+    state_file: PathBuf,
+
+    /// Users that may manage access with `/allow`, `/deny` and `/users`, they always have access
+    #[clap(long = "admin", help = "Make a user id an admin")]
+    admins: Vec<i64>,
+
+    /// Only these users may use the bot, together with the members of allowed chats
+    /// The access lists can be changed at runtime, these are merged into the stored lists and
+    /// denies given here always win
+    #[clap(long = "allow-user", help = "Allow a user id to use the bot")]
+    allowed_users: Vec<i64>,
+
+    #[clap(long = "deny-user", help = "Deny a user id the use of the bot")]
+    denied_users: Vec<i64>,
+
+    /// Group ids are negative, e.g. `--allow-chat -1001234567890`
+    #[clap(
+        long = "allow-chat",
+        allow_negative_numbers = true,
+        help = "Allow everyone in a chat id to use the bot"
+    )]
+    allowed_chats: Vec<i64>,
+
+    #[clap(
+        long = "deny-chat",
+        allow_negative_numbers = true,
+        help = "Deny everyone in a chat id the use of the bot"
+    )]
+    denied_chats: Vec<i64>,
+
+    /// `/frog` requests a minute a single user may make, short bursts are allowed
+    #[clap(long, help = "Limit the questions a minute per user")]
+    user_rate_limit: Option<u32>,
+
+    /// `/frog` requests a minute allowed in a single chat, across all its users
+    #[clap(long, help = "Limit the questions a minute per chat")]
+    chat_rate_limit: Option<u32>,
+
+    /// Quotas per user, days and months are counted in UTC
+    #[clap(long, help = "Limit the questions per user per day")]
+    daily_requests: Option<u64>,
+
+    #[clap(long, help = "Limit the questions per user per month")]
+    monthly_requests: Option<u64>,
+
+    #[clap(long, help = "Limit the tokens per user per day")]
+    daily_tokens: Option<u64>,
+
+    #[clap(long, help = "Limit the tokens per user per month")]
+    monthly_tokens: Option<u64>,
+
+    /// JSON file with the curated list of models and their aliases, see `model::ModelRegistry`
+    #[clap(long, help = "Load the list of models and aliases from a JSON file")]
+    models_file: Option<PathBuf>,
+
+    /// JSON file with the personas chats can switch to, see `persona::Personas`
+    #[clap(long, help = "Load the personas from a JSON file")]
+    personas_file: Option<PathBuf>,
+
+    /// Fetch the models OpenRouter currently serves at startup, so any exact slug can be picked
+    #[clap(long, help = "Refresh the model list from OpenRouter at startup")]
+    refresh_models: bool,
+
+    /// Maximum number of updates handled at the same time, across all chats
+    /// Messages within one chat are always handled in order
+    #[clap(
+        long,
+        default_value = "8",
+        help = "Set the maximum number of updates handled concurrently"
+    )]
+    max_concurrent_updates: usize,
+
+    /// Answer every message in a private chat, not just `/frog`. Groups still need a command
+    #[clap(long, help = "Treat plain text in private chats as a question")]
+    private_prompts: bool,
+
+    /// Stream answers from OpenRouter and show them while they are being written
+    #[clap(
+        long,
+        help = "Stream answers and update the reply while it is generated"
+    )]
+    stream: bool,
+
+    /// Telegram rate limits message edits, so don't edit a streamed answer more often than this
+    #[clap(
+        long,
+        default_value = "1500",
+        help = "Set the minimum time between edits of a streamed answer in milliseconds"
+    )]
+    stream_edit_interval: u64,
+
+    /// Answers longer than one Telegram message are split over several messages. When that
+    /// takes more than this many messages the answer is sent as a markdown file instead
+    #[clap(
+        long,
+        help = "Send answers that need more than this many messages as a file"
+    )]
+    document_after_chunks: Option<usize>,
+
+    /// How long to wait for a completion before telling the user the model is too slow
+    /// Streamed answers may take longer, as long as no more than this passes between parts
+    #[clap(
+        long,
+        default_value = "120",
+        help = "Set the timeout of OpenRouter completions in seconds"
+    )]
+    open_router_timeout: u64,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Mode {
+    /// Ask Telegram for updates with `getUpdates`
+    Polling,
+    /// Let Telegram push updates to an embedded HTTP server
+    Webhook,
 }
 
 /// One single implementation to create a telegram_bot::Config from the command line arguments
@@ -42,7 +270,66 @@ struct Args {
 impl From<&Args> for telegram_bot::Config {
     fn from(args: &Args) -> Self {
         telegram_bot::Config {
-            polling_interval: args.polling_interval,
+            polling_timeout: args.polling_timeout,
+            polling_limit: args.polling_limit,
+            backoff_base: args.backoff_base,
+            backoff_max: args.backoff_max,
+            history_max_turns: args.history_max_turns,
+            history_max_tokens: args.history_max_tokens,
+            default_model: args.default_model.clone(),
+            system_prompt: args
+                .system_prompt
+                .clone()
+                .unwrap_or_else(|| bot_messages::DEFAULT_SYSTEM_PROMPT.to_string()),
+            models_file: args.models_file.clone(),
+            personas_file: args.personas_file.clone(),
+            max_concurrent_updates: args.max_concurrent_updates,
+            private_prompts: args.private_prompts,
+            stream: args.stream,
+            stream_edit_interval: args.stream_edit_interval,
+            document_after_chunks: args.document_after_chunks,
+            open_router_timeout: args.open_router_timeout,
+            webhook: match args.mode {
+                Mode::Polling => None,
+                Mode::Webhook => Some(telegram_bot::WebhookConfig {
+                    url: args
+                        .webhook_url
+                        .clone()
+                        .expect("webhook url must be set in webhook mode!"),
+                    listen: args.webhook_listen,
+                    secret: env::var("WEBHOOK_SECRET")
+                        .expect("webhook secret must be set in webhook mode!"),
+                }),
+            },
+            per_user_model: args.per_user_model,
+            admins: args.admins.iter().copied().collect(),
+            user_rate_limit: args.user_rate_limit,
+            chat_rate_limit: args.chat_rate_limit,
+            quotas: limits::Quotas {
+                daily_requests: args.daily_requests,
+                monthly_requests: args.monthly_requests,
+                daily_tokens: args.daily_tokens,
+                monthly_tokens: args.monthly_tokens,
+            },
+            access: access::AccessLists {
+                allowed_users: args.allowed_users.iter().copied().collect(),
+                denied_users: args.denied_users.iter().copied().collect(),
+                allowed_chats: args.allowed_chats.iter().copied().collect(),
+                denied_chats: args.denied_chats.iter().copied().collect(),
+            },
+            default_params: args.params.iter().fold(
+                params::SamplingParams::default(),
+                |mut params, param| {
+                    let (name, value) = param
+                        .split_once('=')
+                        .expect("parameters must be given as name=value!");
+                    params
+                        .set(name.trim(), value)
+                        .expect("parameters must be valid!");
+                    params
+                },
+            ),
+            state_file: Some(args.state_file.clone()),
             tg_bot_key: env::var("TG_BOT_KEY").expect("bot key must be set!"),
             open_router_key: env::var("OPEN_ROUTER_KEY").expect("open-router key must be set!"),
         }
@@ -56,7 +343,22 @@ impl Args {
         let cfg: telegram_bot::Config = self.into();
 
         // Create a new Telegram bot instance with the config
-        let mut bot = TgBot::new(cfg);
+        let mut bot = TgBot::new(cfg)?;
+
+        // Commands in groups can be addressed to a bot by username, so we need to know ours
+        bot.fetch_me().await?;
+
+        // Show the commands in the menu of Telegram clients, they still work without it
+        if let Err(e) = bot.register_commands().await {
+            tracing::error!(?e, "Failed to register commands");
+        }
+
+        // Learn which models OpenRouter serves, the curated list still works if this fails
+        if self.refresh_models {
+            if let Err(e) = bot.refresh_models().await {
+                tracing::error!(?e, "Failed to refresh models");
+            }
+        }
 
         // Run the bot
         bot.run().await
@@ -0,0 +1,187 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+
+use crate::error::Error;
+use crate::messages::telegram::{Destination, Message};
+use crate::telegram_bot::TgBot;
+
+/// Render a model's Markdown (CommonMark) answer as the HTML Telegram understands
+/// Telegram only knows a handful of tags, so headings become bold lines, lists get bullets
+/// or numbers and anything else, raw HTML included, is shown as escaped text
+pub fn to_telegram_html(markdown: &str) -> String {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut html = String::new();
+    // The next number of every open list, `None` for bullet lists
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Heading { .. } | Tag::Strong => html.push_str("<b>"),
+                Tag::Emphasis => html.push_str("<i>"),
+                Tag::Strikethrough => html.push_str("<s>"),
+                Tag::BlockQuote(_) => html.push_str("<blockquote>"),
+                Tag::CodeBlock(CodeBlockKind::Fenced(language)) if !language.is_empty() => {
+                    let language = language.split_whitespace().next().unwrap_or_default();
+                    html.push_str(&format!(
+                        "<pre><code class=\"language-{}\">",
+                        escape(language)
+                    ));
+                }
+                Tag::CodeBlock(_) => html.push_str("<pre><code>"),
+                Tag::List(start) => {
+                    new_line(&mut html);
+                    lists.push(start);
+                }
+                Tag::Item => {
+                    new_line(&mut html);
+                    html.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                    match lists.last_mut() {
+                        Some(Some(number)) => {
+                            html.push_str(&format!("{}. ", number));
+                            *number += 1;
+                        }
+                        _ => html.push_str("• "),
+                    }
+                }
+                Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                    html.push_str(&format!("<a href=\"{}\">", escape(&dest_url)));
+                }
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                TagEnd::Heading(_) => html.push_str("</b>\n\n"),
+                TagEnd::Strong => html.push_str("</b>"),
+                TagEnd::Emphasis => html.push_str("</i>"),
+                TagEnd::Strikethrough => html.push_str("</s>"),
+                TagEnd::BlockQuote(_) => {
+                    trim_end(&mut html);
+                    html.push_str("</blockquote>\n\n");
+                }
+                TagEnd::CodeBlock => {
+                    trim_end(&mut html);
+                    html.push_str("</code></pre>\n\n");
+                }
+                TagEnd::List(_) => {
+                    lists.pop();
+                    if lists.is_empty() {
+                        html.push('\n');
+                    }
+                }
+                TagEnd::Item => new_line(&mut html),
+                // Paragraphs within list items are kept together
+                TagEnd::Paragraph if !lists.is_empty() => new_line(&mut html),
+                TagEnd::Paragraph => html.push_str("\n\n"),
+                TagEnd::Link | TagEnd::Image => html.push_str("</a>"),
+                _ => {}
+            },
+            Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) => {
+                html.push_str(&escape(&text))
+            }
+            Event::Code(code) => html.push_str(&format!("<code>{}</code>", escape(&code))),
+            Event::SoftBreak | Event::HardBreak => html.push('\n'),
+            Event::Rule => html.push_str("──────────\n\n"),
+            Event::TaskListMarker(done) => html.push_str(if done { "☑ " } else { "☐ " }),
+            _ => {}
+        }
+    }
+    html.trim().to_string()
+}
+
+/// Start a new line, unless the text is already at the start of one
+fn new_line(html: &mut String) {
+    if !html.is_empty() && !html.ends_with('\n') {
+        html.push('\n');
+    }
+}
+
+fn trim_end(html: &mut String) {
+    html.truncate(html.trim_end().len());
+}
+
+/// Escape the characters Telegram's HTML parser would read as markup
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl TgBot {
+    /// Send an answer written in Markdown, rendered as HTML
+    /// Telegram refuses the whole message when it can't parse the HTML, or when rendering made
+    /// it too long, it's resent as the plain Markdown then
+    pub async fn send_markdown(&self, to: &Destination, markdown: &str) -> Result<Message, Error> {
+        match self
+            .send_text(to, &to_telegram_html(markdown), Some("HTML"))
+            .await
+        {
+            Err(e) if e.is_bad_request() => {
+                tracing::warn!(?e, "Telegram refused the HTML, sending plain text");
+                self.send_message(to, markdown).await
+            }
+            result => result,
+        }
+    }
+
+    /// Replace the text of a message the bot sent earlier with Markdown, rendered as HTML
+    /// Falls back to the plain Markdown the same way as `send_markdown`
+    pub async fn edit_message_markdown(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        markdown: &str,
+    ) -> Result<(), Error> {
+        match self
+            .edit_text(
+                chat_id,
+                message_id,
+                &to_telegram_html(markdown),
+                Some("HTML"),
+            )
+            .await
+        {
+            // The rendered text is already shown, the plain text would only look worse
+            Err(e) if e.is_not_modified() => Ok(()),
+            Err(e) if e.is_bad_request() => {
+                tracing::warn!(?e, "Telegram refused the HTML, editing in plain text");
+                self.edit_message_text(chat_id, message_id, markdown).await
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_telegram_html() {
+        let markdown = "# Frogs\n\
+            Frogs are **amphibians**, see [wiki](https://en.wikipedia.org/wiki/Frog?a=1&b=2).\n\n\
+            - *green* ones\n\
+            - `a < b` ones\n\n\
+            1. jump\n\
+            2. swim\n\n\
+            ```rust\nfn main() {\n    println!(\"<ribbit>\");\n}\n```\n\n\
+            <script>alert(1)</script>";
+        assert_eq!(
+            to_telegram_html(markdown),
+            "<b>Frogs</b>\n\n\
+            Frogs are <b>amphibians</b>, see \
+            <a href=\"https://en.wikipedia.org/wiki/Frog?a=1&amp;b=2\">wiki</a>.\n\n\
+            • <i>green</i> ones\n\
+            • <code>a &lt; b</code> ones\n\n\
+            1. jump\n\
+            2. swim\n\n\
+            <pre><code class=\"language-rust\">fn main() {\n    \
+            println!(&quot;&lt;ribbit&gt;&quot;);\n}</code></pre>\n\n\
+            &lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+
+        // An answer cut off in the middle of a code block, as while streaming, still renders
+        assert_eq!(
+            to_telegram_html("look:\n```\nlet x"),
+            "look:\n\n<pre><code>let x</code></pre>"
+        );
+    }
+}
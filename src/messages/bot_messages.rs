@@ -1,5 +1,25 @@
 pub const INITIAL_MESSAGE: &str = "Hello, i'm FrogAI. I'm here to answer all your questions. Just type /frog and ask a question :) ";
-pub const MODEL_LIST: &str =
-        "currently available models are: weaver, unslopnemo, gemini, deepseek, claude, llama, open-ai. To pick a model type: /change_model 'model name'";
-pub const PROMPT: &str =
-    "please limit your answer to 1200 characters. answer the following question: ";
+pub const DEFAULT_SYSTEM_PROMPT: &str =
+    "You are FrogAI, a helpful assistant in a Telegram chat. Please limit your answers to 1200 characters.";
+pub const SYSTEM_PROMPT_RESET: &str = "the system prompt is back to the default.";
+pub const SYSTEM_PROMPT_SET: &str = "changed the system prompt of this chat.";
+pub const STREAM_PLACEHOLDER: &str = "thinking...";
+pub const ANSWER_AS_DOCUMENT: &str =
+    "the answer is too long for a message, so here it is as a file.";
+pub const ERROR_RATE_LIMITED: &str =
+    "too many questions at once, the model is rate limited. please try again in a minute.";
+pub const ERROR_QUOTA: &str =
+    "i'm out of OpenRouter credits, so i can't answer right now. please let the bot owner know.";
+pub const ERROR_MODERATION: &str = "the model refused this question because it was flagged";
+pub const ERROR_PROVIDER_DOWN: &str =
+    "the model is unavailable right now. try again later or pick another model with /change_model.";
+pub const ERROR_TIMEOUT: &str =
+    "the model took too long to answer. please try again or pick a faster model.";
+pub const ERROR_BAD_REQUEST: &str = "OpenRouter didn't accept the question:";
+pub const ERROR_OPEN_ROUTER: &str = "something went wrong at OpenRouter, please try again later.";
+pub const PERSONA_DEFAULT: &str = "no persona picked, i'm using the default system prompt.";
+pub const PARAMS_RESET: &str = "all parameters of this chat are back to the defaults.";
+pub const NOT_ALLOWED: &str = "sorry, you're not allowed to use this bot.";
+pub const ADMIN_ONLY: &str = "only admins can do that.";
+pub const RATE_LIMITED: &str = "you're asking faster than i can keep up, you can ask again in";
+pub const REPLY_CONTEXT: &str = "The next message replies to this earlier message:";
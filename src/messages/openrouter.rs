@@ -1,10 +1,44 @@
 use serde::{Deserialize, Serialize};
 
 use crate::model::Model;
+use crate::params::SamplingParams;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response {
     pub choices: Vec<Choice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+/// Tokens used by a completion, as counted by OpenRouter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// In credits, only sent when asked for with `UsageOptions`
+    #[serde(default)]
+    pub cost: Option<f64>,
+}
+
+/// Asks OpenRouter to include the cost in the usage of a completion
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsageOptions {
+    pub include: bool,
+}
+
+/// Response of OpenRouter when a request failed
+/// Also sent as a chunk when a streamed completion fails halfway
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: u16,
+    pub message: String,
+    /// Details that depend on the kind of error, e.g. the reasons input was flagged
+    pub metadata: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,4 +56,29 @@ pub struct Message {
 pub struct Request {
     pub model: Model,
     pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    pub usage: UsageOptions,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+}
+
+/// A single server-sent event of a streamed completion
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamChunk {
+    pub choices: Vec<StreamChoice>,
+    /// Only sent with the last chunk
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamChoice {
+    pub delta: Delta,
+}
+
+/// The piece of the answer added by a chunk, the first and last chunks carry no content
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Delta {
+    pub content: Option<String>,
 }
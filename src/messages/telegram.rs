@@ -1,9 +1,32 @@
 use serde::{Deserialize, Serialize};
 
+use crate::utils::utf16_to_byte;
+
+/// Response of the Telegram bot API, `result` depends on the method that was called
+/// Defaults to the result of `getUpdates`
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Response {
+pub struct Response<T = Vec<Update>> {
     pub ok: bool,
-    pub result: Vec<Update>,
+    pub result: T,
+}
+
+/// Response of the Telegram bot API when a call failed (`ok` is false)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub ok: bool,
+    pub error_code: i64,
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<ResponseParameters>,
+}
+
+/// Extra information on why a call failed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseParameters {
+    /// Seconds to wait before repeating a request that hit a rate limit
+    pub retry_after: Option<u64>,
+    /// The chat was upgraded to a supergroup with this id
+    pub migrate_to_chat_id: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -23,6 +46,103 @@ pub struct Message {
     pub entities: Option<Vec<MessageEntity>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub group_chat_created: Option<bool>,
+    /// The message this one replies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to_message: Option<Box<Message>>,
+    /// The thread of replies the message is in, or its topic in a forum supergroup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_thread_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_topic_message: Option<bool>,
+}
+
+impl Message {
+    /// The forum topic the message was sent in
+    /// Outside forums `message_thread_id` only marks a thread of replies, which isn't a topic
+    pub fn topic_id(&self) -> Option<i64> {
+        self.message_thread_id
+            .filter(|_| self.is_topic_message == Some(true))
+    }
+
+    /// The message this one replies to
+    /// Messages in a forum topic reply to the message that created the topic when they don't
+    /// reply to anything else, that one isn't counted
+    pub fn replied_to(&self) -> Option<&Message> {
+        self.reply_to_message
+            .as_deref()
+            .filter(|reply| Some(reply.message_id) != self.topic_id())
+    }
+
+    /// The text without the `@username` mentions of a user, `None` when they aren't mentioned
+    pub fn without_mention(&self, username: &str) -> Option<String> {
+        let text = self.text.as_deref()?;
+        let mut rest = String::new();
+        let mut last = 0;
+        let mut mentioned = false;
+        for entity in self.entities.iter().flatten() {
+            if entity.type_ != "mention" {
+                continue;
+            }
+            let (Some(start), Some(end)) = (
+                utf16_to_byte(text, entity.offset),
+                utf16_to_byte(text, entity.offset + entity.length),
+            ) else {
+                continue;
+            };
+            if start >= last
+                && text[start..end]
+                    .trim_start_matches('@')
+                    .eq_ignore_ascii_case(username)
+            {
+                rest.push_str(&text[last..start]);
+                last = end;
+                mentioned = true;
+            }
+        }
+        rest.push_str(&text[last..]);
+        mentioned.then(|| rest.trim().to_string())
+    }
+}
+
+/// Where the bot sends a message: a chat, the forum topic in it and the message it answers
+/// Serializes to the parameters Telegram's send methods take
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Destination {
+    pub chat_id: i64,
+    #[serde(rename = "message_thread_id", skip_serializing_if = "Option::is_none")]
+    pub topic_id: Option<i64>,
+    #[serde(rename = "reply_parameters", skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyParameters>,
+}
+
+impl Destination {
+    /// A reply to `message`, in the topic it was sent in
+    pub fn reply_to(message: &Message) -> Self {
+        Destination {
+            chat_id: message.chat.get_id(),
+            topic_id: message.topic_id(),
+            reply_to: Some(ReplyParameters {
+                message_id: message.message_id,
+                allow_sending_without_reply: true,
+            }),
+        }
+    }
+
+    /// The same chat and topic, without replying to anything
+    /// Used for the rest of an answer that takes several messages
+    pub fn without_reply(self) -> Self {
+        Destination {
+            reply_to: None,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ReplyParameters {
+    pub message_id: i64,
+    /// Still send the message when the question was deleted in the meantime
+    pub allow_sending_without_reply: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,6 +153,13 @@ pub struct MessageEntity {
     pub type_: String,
 }
 
+/// A command as shown in the command menu of a Telegram client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BotCommand {
+    pub command: String,
+    pub description: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
@@ -78,6 +205,10 @@ impl Chat {
             Chat::Channel { id, .. } => *id,
         }
     }
+
+    pub fn is_private(&self) -> bool {
+        matches!(self, Chat::Private { .. })
+    }
 }
 
 #[cfg(test)]
@@ -425,4 +556,91 @@ mod tests {
             _ => panic!("Expected Group chat"),
         }
     }
+
+    #[test]
+    fn test_mentions_and_replies() {
+        let message: Message = serde_json::from_str(
+            r#"{
+                "message_id": 505,
+                "from": { "id": 1700048531, "first_name": "Doug" },
+                "chat": { "id": -4676384907, "title": "Doug and FrogAI", "type": "group" },
+                "date": 1740530690,
+                "text": "🐸 @FrogAIBot what does @beek_en_donk mean?",
+                "entities": [
+                    { "offset": 3, "length": 10, "type": "mention" },
+                    { "offset": 24, "length": 13, "type": "mention" }
+                ],
+                "reply_to_message": {
+                    "message_id": 504,
+                    "from": { "id": 42, "is_bot": true, "first_name": "FrogAI" },
+                    "chat": { "id": -4676384907, "title": "Doug and FrogAI", "type": "group" },
+                    "date": 1740530685,
+                    "text": "ribbit"
+                }
+            }"#,
+        )
+        .expect("Failed to parse message");
+        assert_eq!(
+            message.without_mention("frogaibot").as_deref(),
+            Some("🐸  what does @beek_en_donk mean?")
+        );
+        assert_eq!(message.without_mention("OtherBot"), None);
+        let reply = message.replied_to().unwrap();
+        assert_eq!(reply.from.id, 42);
+        assert_eq!(reply.text.as_deref(), Some("ribbit"));
+        assert_eq!(message.topic_id(), None);
+
+        // In a forum topic the message replies to the topic's first message, which isn't a reply
+        let message: Message = serde_json::from_str(
+            r#"{
+                "message_id": 512,
+                "from": { "id": 1700048531, "first_name": "Doug" },
+                "chat": { "id": -1001676384907, "title": "Frog Forum", "type": "supergroup" },
+                "date": 1740530690,
+                "text": "/frog ribbit?",
+                "message_thread_id": 510,
+                "is_topic_message": true,
+                "reply_to_message": {
+                    "message_id": 510,
+                    "from": { "id": 1700048531, "first_name": "Doug" },
+                    "chat": { "id": -1001676384907, "title": "Frog Forum", "type": "supergroup" },
+                    "date": 1740530600
+                }
+            }"#,
+        )
+        .expect("Failed to parse message");
+        assert_eq!(message.topic_id(), Some(510));
+        assert!(message.replied_to().is_none());
+        assert_eq!(
+            serde_json::to_value(Destination::reply_to(&message)).unwrap(),
+            serde_json::json!({
+                "chat_id": -1001676384907i64,
+                "message_thread_id": 510,
+                "reply_parameters": { "message_id": 512, "allow_sending_without_reply": true },
+            })
+        );
+    }
+
+    #[test]
+    fn test_deserialize_error_response() {
+        let response: ErrorResponse = serde_json::from_str(
+            r#"{
+                "ok": false,
+                "error_code": 429,
+                "description": "Too Many Requests: retry after 5",
+                "parameters": { "retry_after": 5 }
+            }"#,
+        )
+        .expect("Failed to parse error response");
+        assert!(!response.ok);
+        assert_eq!(response.error_code, 429);
+        assert_eq!(response.parameters.unwrap().retry_after, Some(5));
+
+        let response: ErrorResponse = serde_json::from_str(
+            r#"{ "ok": false, "error_code": 400, "description": "Bad Request: message is too long" }"#,
+        )
+        .expect("Failed to parse error response");
+        assert_eq!(response.description, "Bad Request: message is too long");
+        assert!(response.parameters.is_none());
+    }
 }
@@ -1,84 +1,208 @@
+use std::collections::HashSet;
+use std::path::Path;
+
 use serde::{Deserialize, Serialize};
 
-/// Model enum
-/// Capture the different models that can be used
-/// The default model is OpenAi
-#[derive(Default, Clone, Copy, Debug)]
-pub enum Model {
-    Weaver,
-    Unslopnemo,
-    Gemini,
-    Deepseek,
-    Claude,
-    Llama,
-    #[default]
-    OpenAi,
+use crate::constants::OPEN_ROUTER_MODELS_URL;
+use crate::error::Error;
+
+/// Model
+/// The OpenRouter slug of a model, e.g. `anthropic/claude-3.5-sonnet`
+/// Serializes as the plain slug so it can be put straight into a request
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Model(String);
+
+impl Model {
+    pub fn new(slug: impl Into<String>) -> Self {
+        Model(slug.into())
+    }
 }
 
 /// Implement Display for Model
-/// This gives the `Model` enum the `to_string` and `as_str` methods
 /// Also allows for the `format!` macro to be used
 impl std::fmt::Display for Model {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", <&str>::from(*self))
+        write!(f, "{}", self.0)
     }
 }
 
-/// Implement From<Model> for &str
-/// This allows for us to cast a `Model` to a `&str`
-impl From<Model> for &str {
-    fn from(model: Model) -> Self {
-        match model {
-            Model::Unslopnemo => "thedrummer/unslopnemo-12b",
-            Model::Gemini => "google/gemini-2.0-flash-001",
-            Model::Deepseek => "deepseek/deepseek-r1-distill-llama-8b",
-            Model::Claude => "anthropic/claude-3.5-sonnet",
-            Model::Llama => "sao10k/13.1-70b-hanami-x1",
-            Model::OpenAi => "openai/gpt-4o",
-            Model::Weaver => "mancer/weaver",
+/// A model as listed by `/list_models`, with the short names it can be picked by
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub slug: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+impl ModelInfo {
+    fn new(slug: &str, aliases: &[&str]) -> Self {
+        ModelInfo {
+            slug: slug.to_string(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
         }
     }
 }
 
-/// Implement From<&str> for Model
-/// This allows for us to cast a `&str` to a `Model`
-impl TryFrom<&str> for Model {
-    type Error = ();
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        Ok(match value {
-            _ if value.contains("weaver") => Self::Weaver,
-            _ if value.contains("unslopnemo") => Self::Unslopnemo,
-            _ if value.contains("gemini") => Self::Gemini,
-            _ if value.contains("deepseek") => Self::Deepseek,
-            _ if value.contains("claude") => Self::Claude,
-            _ if value.contains("llama") => Self::Llama,
-            _ if value.contains("openai") => Self::OpenAi,
-            _ if value.contains("gpt") => Self::OpenAi,
-            _ => return Err(()),
-        })
-    }
+/// Layout of the models file
+/// ```json
+/// { "models": [{ "slug": "anthropic/claude-3.5-sonnet", "aliases": ["claude"] }] }
+/// ```
+#[derive(Debug, Deserialize)]
+struct ModelsFile {
+    models: Vec<ModelInfo>,
 }
 
-/// Custom serialization implementation to use the Display format
-impl Serialize for Model {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_str(<&str>::from(*self))
+/// Layout of OpenRouter's `/api/v1/models` response, we only care about the slugs
+#[derive(Debug, Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelsResponseEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelsResponseEntry {
+    id: String,
+}
+
+/// Model registry
+/// `models` is the curated list shown to users, `available` holds every slug OpenRouter
+/// currently serves. Until the registry has been refreshed only curated models are accepted,
+/// after that curated models OpenRouter doesn't serve are hidden but kept, so they come back
+/// when a later refresh lists them again
+#[derive(Debug)]
+pub struct ModelRegistry {
+    models: Vec<ModelInfo>,
+    available: HashSet<String>,
+}
+
+/// The models the bot shipped with before the registry could be configured
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        ModelRegistry {
+            models: vec![
+                ModelInfo::new("mancer/weaver", &["weaver"]),
+                ModelInfo::new("thedrummer/unslopnemo-12b", &["unslopnemo"]),
+                ModelInfo::new("google/gemini-2.0-flash-001", &["gemini"]),
+                ModelInfo::new("deepseek/deepseek-r1-distill-llama-8b", &["deepseek"]),
+                ModelInfo::new("anthropic/claude-3.5-sonnet", &["claude"]),
+                ModelInfo::new("sao10k/13.1-70b-hanami-x1", &["llama"]),
+                ModelInfo::new("openai/gpt-4o", &["openai", "open-ai", "gpt"]),
+            ],
+            available: HashSet::new(),
+        }
     }
 }
 
-/// Custom deserialization implementation
-impl<'de> Deserialize<'de> for Model {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        Model::try_from(s.as_str()).map_err(|_| {
-            serde::de::Error::custom(format!("unknown model type: {}", s))
+impl ModelRegistry {
+    /// Load the curated model list from a JSON models file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file: ModelsFile = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(ModelRegistry {
+            models: file.models,
+            available: HashSet::new(),
         })
     }
+
+    /// Fetch the slugs OpenRouter currently serves
+    /// An empty answer is ignored, it would hide every curated model
+    pub async fn refresh(&mut self, http_client: &reqwest::Client) -> Result<(), Error> {
+        let response: ModelsResponse = http_client
+            .get(OPEN_ROUTER_MODELS_URL)
+            .send()
+            .await?
+            .json()
+            .await?;
+        if response.data.is_empty() {
+            tracing::warn!("OpenRouter listed no models, keeping the model registry as it is");
+            return Ok(());
+        }
+        self.available = response.data.into_iter().map(|entry| entry.id).collect();
+        for model in &self.models {
+            if !self.available.contains(&model.slug) {
+                tracing::warn!(slug = model.slug, "model is no longer served by OpenRouter");
+            }
+        }
+        tracing::info!(count = self.available.len(), "refreshed model registry");
+        Ok(())
+    }
+
+    /// The curated models OpenRouter serves, all of them until the registry is refreshed
+    fn served(&self) -> impl Iterator<Item = &ModelInfo> {
+        self.models
+            .iter()
+            .filter(|model| self.available.is_empty() || self.available.contains(&model.slug))
+    }
+
+    /// Resolve an alias or an exact slug to a model, case insensitive
+    pub fn resolve(&self, name: &str) -> Option<Model> {
+        let name = name.trim().to_lowercase();
+        if let Some(model) = self.served().find(|model| {
+            model.slug.to_lowercase() == name
+                || model
+                    .aliases
+                    .iter()
+                    .any(|alias| alias.to_lowercase() == name)
+        }) {
+            return Some(Model::new(&model.slug));
+        }
+        self.available
+            .iter()
+            .find(|slug| slug.to_lowercase() == name)
+            .map(Model::new)
+    }
+
+    /// The curated models, formatted for `/list_models`
+    pub fn list(&self) -> String {
+        let mut list = String::from("currently available models are:");
+        for model in self.served() {
+            match model.aliases.first() {
+                Some(alias) => list.push_str(&format!("\n- {} ({})", alias, model.slug)),
+                None => list.push_str(&format!("\n- {}", model.slug)),
+            }
+        }
+        if !self.available.is_empty() {
+            list.push_str(&format!(
+                "\nany of the {} models on OpenRouter can also be picked by their exact name.",
+                self.available.len()
+            ));
+        }
+        list.push_str("\nTo pick a model type: /change_model 'model name'");
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_models() {
+        let mut registry = ModelRegistry::default();
+        assert_eq!(
+            registry.resolve("Claude"),
+            Some(Model::new("anthropic/claude-3.5-sonnet"))
+        );
+        assert_eq!(
+            registry.resolve("openai/gpt-4o"),
+            Some(Model::new("openai/gpt-4o"))
+        );
+        // No more substring matching
+        assert_eq!(registry.resolve("claude-3"), None);
+        assert_eq!(registry.resolve("mistralai/mistral-7b-instruct"), None);
+
+        registry.available = HashSet::from([
+            "mistralai/mistral-7b-instruct".to_string(),
+            "openai/gpt-4o".to_string(),
+        ]);
+        assert_eq!(
+            registry.resolve("mistralai/mistral-7b-instruct"),
+            Some(Model::new("mistralai/mistral-7b-instruct"))
+        );
+        // Curated models OpenRouter doesn't serve are hidden, not dropped
+        assert_eq!(registry.resolve("gpt"), Some(Model::new("openai/gpt-4o")));
+        assert_eq!(registry.resolve("claude"), None);
+        assert!(!registry.list().contains("claude"));
+        registry.available.insert("anthropic/claude-3.5-sonnet".to_string());
+        assert!(registry.list().contains("claude"));
+    }
 }
@@ -1,9 +1,22 @@
 use crate::constants::OPEN_ROUTER_COMPLETIONS_URL;
+use crate::history::Conversation;
 use crate::messages;
-use crate::messages::openrouter::{Message, Request};
+use crate::messages::bot_messages;
+use crate::messages::openrouter::{ErrorResponse, Message, Request, Usage, UsageOptions};
+use crate::messages::telegram;
+use crate::model::Model;
 use crate::telegram_bot::TgBot;
 use crate::Error;
-use messages::bot_messages;
+use std::time::Duration;
+
+/// A complete answer to a question
+pub struct Answer {
+    pub text: String,
+    /// The model that was asked
+    pub model: Model,
+    /// What the answer used, when OpenRouter reported it
+    pub usage: Option<Usage>,
+}
 
 // NOTE: Simplified the open router calling logic and added it to the TgBot struct.
 // This makes it so that fields like `model` are always consistent with the bot instance.
@@ -13,43 +26,199 @@ use messages::bot_messages;
 // NOTE: Also removed use of `json` macro in favor of constructing the JSON object as a struct,
 // which is then serialized to JSON. This makes it easier to make changes later.
 impl TgBot {
-    pub async fn call_open_router(&self, message: &str) -> Result<Vec<String>, Error> {
-        let request = Request {
-            model: self.model(),
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: format!("{}{}", bot_messages::PROMPT, message),
-            }],
-        };
+    /// Build the completion request for the question `message` asks, with the chat's system
+    /// prompt and history. When the message replies to another, that one is included as context
+    pub fn completion_request(
+        &self,
+        message: &telegram::Message,
+        question: &str,
+        stream: bool,
+    ) -> Request {
+        let (chat_id, user_id) = (message.chat.get_id(), message.from.id);
+        // The system prompt goes first, then earlier exchanges in this chat, or topic, are
+        // replayed so follow-up questions make sense
+        let mut messages = vec![Message {
+            role: "system".to_string(),
+            content: self.system_prompt(chat_id),
+        }];
+        messages.extend(self.conversation(Conversation::of(message)));
+        // A reply to the latest answer doesn't need it again, it was just replayed
+        let last = messages.last().map(|last| last.content.trim().to_string());
+        if let Some((reply_to, text)) = message
+            .replied_to()
+            .and_then(|reply_to| Some((reply_to, reply_to.text.as_deref()?)))
+            .filter(|(_, text)| last.as_deref() != Some(text.trim()))
+        {
+            messages.push(if self.is_me(&reply_to.from) {
+                Message {
+                    role: "assistant".to_string(),
+                    content: text.to_string(),
+                }
+            } else {
+                Message {
+                    role: "user".to_string(),
+                    content: format!("{}\n{}", bot_messages::REPLY_CONTEXT, text),
+                }
+            });
+        }
+        messages.push(Message {
+            role: "user".to_string(),
+            content: question.to_string(),
+        });
+        Request {
+            model: self.model(chat_id, user_id),
+            messages,
+            stream: stream.then_some(true),
+            usage: UsageOptions { include: true },
+            params: self.sampling_params(chat_id),
+        }
+    }
 
-        let req = self
+    /// Send a completion request to OpenRouter
+    /// A failed request is turned into the matching OpenRouter error
+    pub async fn send_open_router(&self, request: &Request) -> Result<reqwest::Response, Error> {
+        let mut req = self
             .http_client
             .post(OPEN_ROUTER_COMPLETIONS_URL)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.open_router_key()),
-            )
+            .bearer_auth(self.open_router_key())
             .header("Content-Type", "application/json")
-            .json(&request)
-            .build()?;
+            .json(request);
+        let timeout = Duration::from_secs(self.cfg().open_router_timeout);
+        // A streamed answer may take longer than the timeout to arrive, as long as it keeps
+        // arriving. Only waiting for it to start is limited here, the stream checks the rest
+        if request.stream.is_none() {
+            req = req.timeout(timeout);
+        }
+        let req = req.build().map_err(request_error)?;
 
-        println!("{:?}", req);
+        tracing::debug!(model = %request.model, url = %req.url(), "sending completion request");
+        let response = tokio::time::timeout(timeout, self.http_client.execute(req))
+            .await
+            .map_err(|_| Error::OpenRouterTimeout)?
+            .map_err(request_error)?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let body = response.text().await.map_err(request_error)?;
+        Err(match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(error) => error.into(),
+            Err(_) => Error::OpenRouter {
+                code: status.as_u16(),
+                message: body,
+            },
+        })
+    }
+
+    pub async fn call_open_router(
+        &self,
+        message: &telegram::Message,
+        question: &str,
+    ) -> Result<Answer, Error> {
+        let request = self.completion_request(message, question, false);
         let response = self
-            .http_client
-            .execute(req)
+            .send_open_router(&request)
             .await?
             .json::<serde_json::Value>()
-            .await?;
+            .await
+            .map_err(request_error)?;
 
-        println!("{:?}", response);
+        tracing::debug!(?response, "completion response");
 
-        let response = serde_json::from_value::<messages::openrouter::Response>(response)?;
+        // OpenRouter can also report errors with a 200 status, e.g. when the provider fails
+        if response.get("error").is_some() {
+            return Err(serde_json::from_value::<ErrorResponse>(response)
+                .map_err(invalid_response)?
+                .into());
+        }
+
+        let response = serde_json::from_value::<messages::openrouter::Response>(response)
+            .map_err(invalid_response)?;
 
         let mut result = Vec::new();
         for update in response.choices {
             let r = update.message.content;
             result.push(r);
         }
-        Ok(result)
+        Ok(Answer {
+            text: result.join("\n"),
+            model: request.model,
+            usage: response.usage,
+        })
+    }
+}
+
+/// A request to OpenRouter that failed on the way, e.g. it couldn't connect or timed out
+/// Turned into an OpenRouter error rather than a plain HTTP one, so the user is told
+pub fn request_error(error: reqwest::Error) -> Error {
+    if error.is_timeout() {
+        Error::OpenRouterTimeout
+    } else {
+        Error::OpenRouter {
+            code: error.status().map_or(0, |status| status.as_u16()),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// OpenRouter answered with something that isn't a completion
+fn invalid_response(error: serde_json::Error) -> Error {
+    Error::OpenRouter {
+        code: 200,
+        message: format!("invalid response: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_context_is_not_repeated() {
+        let bot = TgBot::new(crate::telegram_bot::Config {
+            history_max_turns: 10,
+            history_max_tokens: 1000,
+            default_model: "openai/gpt-4o".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let reply = |text: &str| -> telegram::Message {
+            serde_json::from_value(serde_json::json!({
+                "message_id": 2,
+                "from": { "id": 1, "first_name": "Doug" },
+                "chat": { "id": 1, "first_name": "Doug", "type": "private" },
+                "date": 1740530690,
+                "text": "and then?",
+                "reply_to_message": {
+                    "message_id": 1,
+                    "from": { "id": 42, "is_bot": true, "first_name": "FrogAI" },
+                    "chat": { "id": 1, "first_name": "Doug", "type": "private" },
+                    "date": 1740530685,
+                    "text": text
+                }
+            }))
+            .unwrap()
+        };
+        let message = reply("ribbit");
+        bot.remember(Conversation::of(&message), "what do frogs say?", "ribbit");
+
+        // The system prompt, the remembered exchange and the question
+        let request = bot.completion_request(&message, "and then?", false);
+        assert_eq!(request.messages.len(), 4);
+        // An older message is still added as context
+        let request = bot.completion_request(&reply("croak"), "and then?", false);
+        assert_eq!(request.messages.len(), 5);
+    }
+
+    #[test]
+    fn test_invalid_responses_are_reported() {
+        let error = serde_json::from_str::<messages::openrouter::Response>("{}").unwrap_err();
+        let error = invalid_response(error);
+        assert!(error.is_open_router());
+        assert_eq!(
+            error.user_message().as_deref(),
+            Some(bot_messages::ERROR_OPEN_ROUTER)
+        );
     }
 }
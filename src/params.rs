@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// OpenRouter allows at most this many stop sequences
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Sampling parameters of a completion request
+/// Every parameter is optional, unset parameters are left to the model's defaults
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+/// Names of the parameters, in the order they are shown
+pub const PARAM_NAMES: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "max_tokens",
+    "frequency_penalty",
+    "presence_penalty",
+    "stop",
+    "seed",
+];
+
+impl SamplingParams {
+    pub fn is_empty(&self) -> bool {
+        self == &SamplingParams::default()
+    }
+
+    /// Parameters set in `other` take precedence over the ones in `self`
+    pub fn merge(&self, other: &SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: other.temperature.or(self.temperature),
+            top_p: other.top_p.or(self.top_p),
+            top_k: other.top_k.or(self.top_k),
+            max_tokens: other.max_tokens.or(self.max_tokens),
+            frequency_penalty: other.frequency_penalty.or(self.frequency_penalty),
+            presence_penalty: other.presence_penalty.or(self.presence_penalty),
+            stop: other.stop.clone().or_else(|| self.stop.clone()),
+            seed: other.seed.or(self.seed),
+        }
+    }
+
+    /// Set a parameter from text, checking it is within the range OpenRouter accepts
+    /// `reset` unsets the parameter. Stop sequences are separated by `|`
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let value = value.trim();
+        if value == "reset" {
+            return self.reset(name);
+        }
+        match name {
+            "temperature" => self.temperature = Some(parse_range(name, value, 0.0, 2.0)?),
+            "top_p" => self.top_p = Some(parse_range(name, value, 0.0, 1.0)?),
+            "top_k" => self.top_k = Some(parse(name, value)?),
+            "max_tokens" => {
+                let max_tokens = parse(name, value)?;
+                if max_tokens == 0 {
+                    return Err(Error::InvalidParam(
+                        "max_tokens must be at least 1".to_string(),
+                    ));
+                }
+                self.max_tokens = Some(max_tokens);
+            }
+            "frequency_penalty" => {
+                self.frequency_penalty = Some(parse_range(name, value, -2.0, 2.0)?)
+            }
+            "presence_penalty" => {
+                self.presence_penalty = Some(parse_range(name, value, -2.0, 2.0)?)
+            }
+            "stop" => {
+                let stop: Vec<String> = value
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|sequence| !sequence.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if stop.is_empty() || stop.len() > MAX_STOP_SEQUENCES {
+                    return Err(Error::InvalidParam(format!(
+                        "stop takes 1 to {} sequences separated by |",
+                        MAX_STOP_SEQUENCES
+                    )));
+                }
+                self.stop = Some(stop);
+            }
+            "seed" => self.seed = Some(parse(name, value)?),
+            _ => return Err(unknown_param(name)),
+        }
+        Ok(())
+    }
+
+    /// Unset a parameter so the default applies again
+    pub fn reset(&mut self, name: &str) -> Result<(), Error> {
+        match name {
+            "temperature" => self.temperature = None,
+            "top_p" => self.top_p = None,
+            "top_k" => self.top_k = None,
+            "max_tokens" => self.max_tokens = None,
+            "frequency_penalty" => self.frequency_penalty = None,
+            "presence_penalty" => self.presence_penalty = None,
+            "stop" => self.stop = None,
+            "seed" => self.seed = None,
+            _ => return Err(unknown_param(name)),
+        }
+        Ok(())
+    }
+
+    /// The parameters that are set, formatted for `/params`
+    pub fn describe(&self) -> String {
+        let values = [
+            self.temperature.map(|v| v.to_string()),
+            self.top_p.map(|v| v.to_string()),
+            self.top_k.map(|v| v.to_string()),
+            self.max_tokens.map(|v| v.to_string()),
+            self.frequency_penalty.map(|v| v.to_string()),
+            self.presence_penalty.map(|v| v.to_string()),
+            self.stop.as_ref().map(|v| v.join(" | ")),
+            self.seed.map(|v| v.to_string()),
+        ];
+        let set: Vec<String> = PARAM_NAMES
+            .iter()
+            .zip(values)
+            .filter_map(|(name, value)| value.map(|value| format!("- {}: {}", name, value)))
+            .collect();
+        if set.is_empty() {
+            "all parameters are at the model's defaults.".to_string()
+        } else {
+            format!("current parameters:\n{}", set.join("\n"))
+        }
+    }
+}
+
+fn unknown_param(name: &str) -> Error {
+    Error::InvalidParam(format!(
+        "unknown parameter '{}', known parameters are: {}",
+        name,
+        PARAM_NAMES.join(", ")
+    ))
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidParam(format!("'{}' is not a valid {}", value, name)))
+}
+
+fn parse_range(name: &str, value: &str, min: f32, max: f32) -> Result<f32, Error> {
+    let parsed: f32 = parse(name, value)?;
+    if !(min..=max).contains(&parsed) {
+        return Err(Error::InvalidParam(format!(
+            "{} must be between {} and {}",
+            name, min, max
+        )));
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_merge_params() {
+        let mut params = SamplingParams::default();
+        params.set("temperature", "0.7").unwrap();
+        params.set("stop", "### | END").unwrap();
+        assert_eq!(params.temperature, Some(0.7));
+        assert_eq!(
+            params.stop,
+            Some(vec!["###".to_string(), "END".to_string()])
+        );
+
+        assert!(params.set("temperature", "2.5").is_err());
+        assert!(params.set("top_p", "-0.1").is_err());
+        assert!(params.set("max_tokens", "0").is_err());
+        assert!(params.set("max_tokens", "many").is_err());
+        assert!(params.set("creativity", "1").is_err());
+        assert_eq!(params.temperature, Some(0.7));
+
+        let mut chat = SamplingParams::default();
+        chat.set("max_tokens", "500").unwrap();
+        let merged = params.merge(&chat);
+        assert_eq!(merged.temperature, Some(0.7));
+        assert_eq!(merged.max_tokens, Some(500));
+
+        params.set("temperature", "reset").unwrap();
+        params.reset("stop").unwrap();
+        assert!(params.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::messages::bot_messages;
+
+/// A named personality a chat can switch to with `/persona <name>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Persona {
+    pub name: String,
+    pub system_prompt: String,
+    /// Model used while the chat hasn't picked one with `/change_model`, alias or exact slug
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Sent when switching to the persona and on `/startfrog`
+    #[serde(default)]
+    pub greeting: Option<String>,
+}
+
+impl Persona {
+    pub fn greeting(&self) -> String {
+        self.greeting
+            .clone()
+            .unwrap_or_else(|| format!("i'm now {}.", self.name))
+    }
+}
+
+/// Layout of the personas file
+/// ```json
+/// { "personas": [{ "name": "translator", "system_prompt": "...", "model": "claude",
+///                  "temperature": 0.2, "greeting": "..." }] }
+/// ```
+#[derive(Debug, Deserialize)]
+struct PersonasFile {
+    personas: Vec<Persona>,
+}
+
+/// The personas chats can pick from
+#[derive(Debug)]
+pub struct Personas {
+    personas: Vec<Persona>,
+}
+
+/// The personas the bot comes with when no personas file is configured
+impl Default for Personas {
+    fn default() -> Self {
+        let persona = |name: &str, system_prompt: &str, temperature, greeting: &str| Persona {
+            name: name.to_string(),
+            system_prompt: system_prompt.to_string(),
+            model: None,
+            temperature,
+            greeting: Some(greeting.to_string()),
+        };
+        Personas {
+            personas: vec![
+                persona(
+                    "frog",
+                    bot_messages::DEFAULT_SYSTEM_PROMPT,
+                    None,
+                    bot_messages::INITIAL_MESSAGE,
+                ),
+                persona(
+                    "reviewer",
+                    "You are a senior software engineer reviewing code. Point out bugs, \
+                     unclear naming and missing error handling, most important first. Be direct.",
+                    Some(0.2),
+                    "send me code and i'll review it.",
+                ),
+                persona(
+                    "translator",
+                    "You are a translator. Translate every message to English, or to Dutch when \
+                     it is already in English. Only answer with the translation.",
+                    Some(0.1),
+                    "send me text and i'll translate it.",
+                ),
+                persona(
+                    "terse",
+                    "You explain things as briefly as possible, in at most three sentences.",
+                    None,
+                    "ask me anything, i'll keep it short.",
+                ),
+            ],
+        }
+    }
+}
+
+impl Personas {
+    /// Load the personas from a JSON personas file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file: PersonasFile = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Personas {
+            personas: file.personas,
+        })
+    }
+
+    /// Find a persona by name, case insensitive
+    pub fn get(&self, name: &str) -> Option<&Persona> {
+        self.personas
+            .iter()
+            .find(|persona| persona.name.eq_ignore_ascii_case(name.trim()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Persona> {
+        self.personas.iter()
+    }
+
+    /// The personas, formatted for `/persona list`, marking the active one
+    pub fn list(&self, active: Option<&str>) -> String {
+        let mut list = String::from("available personas are:");
+        for persona in &self.personas {
+            let marker = if Some(persona.name.as_str()) == active {
+                " (active)"
+            } else {
+                ""
+            };
+            list.push_str(&format!("\n- {}{}", persona.name, marker));
+        }
+        list.push_str("\nTo switch persona type: /persona 'name'");
+        list
+    }
+}
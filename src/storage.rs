@@ -0,0 +1,187 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// Storage
+/// Where the bot persists its state: the update offset, per-chat settings, conversation
+/// history and so on. Every piece of state is stored as a JSON value under its own key, so
+/// a backend doesn't need to know what it stores
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// The value stored under `key`, if any
+    fn get(&self, key: &str) -> Result<Option<serde_json::Value>, Error>;
+
+    /// Store `value` under `key`, replacing what was there
+    fn put(&self, key: &str, value: serde_json::Value) -> Result<(), Error>;
+}
+
+/// Typed access on top of the JSON values
+impl dyn Storage {
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error> {
+        self.get(key)?
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(Error::from)
+    }
+
+    pub fn store<T: Serialize>(&self, key: &str, value: &T) -> Result<(), Error> {
+        self.put(key, serde_json::to_value(value)?)
+    }
+}
+
+/// Keeps the state in memory only, everything is lost on restart
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    values: Mutex<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<serde_json::Value>, Error> {
+        Ok(self
+            .values
+            .lock()
+            .expect("storage lock poisoned")
+            .get(key)
+            .cloned())
+    }
+
+    fn put(&self, key: &str, value: serde_json::Value) -> Result<(), Error> {
+        self.values
+            .lock()
+            .expect("storage lock poisoned")
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Keeps the state in a single JSON file
+/// Changes are written by a thread of their own, so storing never waits on the disk. Changes
+/// made while it is writing are written together afterwards. The file is written to a
+/// temporary file first, synced and then renamed over the old one, so a crash halfway through
+/// a write never leaves a broken file
+#[derive(Debug)]
+pub struct JsonFileStorage {
+    values: Arc<Mutex<serde_json::Map<String, serde_json::Value>>>,
+    /// Tells the writer there are changes, taken on drop to stop it
+    changed: Option<mpsc::Sender<()>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl JsonFileStorage {
+    /// Open the state file at `path`, a missing file just means there's no state yet
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        let values = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            serde_json::Map::new()
+        };
+        let values = Arc::new(Mutex::new(values));
+        let (changed, changes) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("state-writer".to_string())
+            .spawn({
+                let values = values.clone();
+                move || write_changes(&path, &values, changes)
+            })?;
+        Ok(JsonFileStorage {
+            values,
+            changed: Some(changed),
+            writer: Some(writer),
+        })
+    }
+}
+
+/// Write the state file every time there are changes, until the storage is dropped
+fn write_changes(
+    path: &Path,
+    values: &Mutex<serde_json::Map<String, serde_json::Value>>,
+    changes: mpsc::Receiver<()>,
+) {
+    while changes.recv().is_ok() {
+        // Everything changed up to now goes into this one write
+        while changes.try_recv().is_ok() {}
+        let contents = serde_json::to_vec(&*values.lock().expect("storage lock poisoned"));
+        let result = contents
+            .map_err(Error::from)
+            .and_then(|contents| write_atomically(path, &contents));
+        if let Err(e) = result {
+            tracing::error!(?e, "Failed to write the state file");
+        }
+    }
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Waits for the changes that haven't been written yet
+impl Drop for JsonFileStorage {
+    fn drop(&mut self) {
+        self.changed.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                tracing::error!("The state file writer panicked");
+            }
+        }
+    }
+}
+
+impl Storage for JsonFileStorage {
+    fn get(&self, key: &str) -> Result<Option<serde_json::Value>, Error> {
+        Ok(self
+            .values
+            .lock()
+            .expect("storage lock poisoned")
+            .get(key)
+            .cloned())
+    }
+
+    /// Writing the file happens later, failing to do so is only logged
+    fn put(&self, key: &str, value: serde_json::Value) -> Result<(), Error> {
+        self.values
+            .lock()
+            .expect("storage lock poisoned")
+            .insert(key.to_string(), value);
+        if let Some(changed) = &self.changed {
+            // Only fails when the writer is gone, which it logged already
+            let _ = changed.send(());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_file_storage_roundtrip() {
+        let path = std::env::temp_dir().join(format!("frog-state-{}.json", std::process::id()));
+        let storage: Box<dyn Storage> = Box::new(JsonFileStorage::open(path.clone()).unwrap());
+        assert_eq!(storage.load::<i64>("offset").unwrap(), None);
+        storage.store("offset", &42i64).unwrap();
+        storage.store("names", &vec!["frog"]).unwrap();
+
+        // Dropping it writes what's left, a fresh instance reads back what was written and no
+        // temporary file is left
+        drop(storage);
+        let storage: Box<dyn Storage> = Box::new(JsonFileStorage::open(path.clone()).unwrap());
+        assert_eq!(storage.load::<i64>("offset").unwrap(), Some(42));
+        assert_eq!(
+            storage.load::<Vec<String>>("names").unwrap(),
+            Some(vec!["frog".to_string()])
+        );
+        assert!(!path.with_extension("tmp").exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
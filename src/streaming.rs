@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use crate::chunking::split_message;
+use crate::constants::TELEGRAM_MESSAGE_LIMIT;
+use crate::error::Error;
+use crate::messages::bot_messages;
+use crate::messages::openrouter::{ErrorResponse, StreamChunk};
+use crate::messages::telegram::{Destination, Message};
+use crate::open_router::Answer;
+use crate::telegram_bot::TgBot;
+
+/// Splits a server-sent event stream into the payloads of its `data:` lines
+/// Chunks from the network can end halfway through a line (or a UTF-8 character),
+/// so incomplete lines are kept until the rest arrives
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    /// Feed a chunk of the stream, returns the data of every line it completed
+    /// Comments (lines starting with `:`) and other fields are skipped
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut data = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(payload) = line.trim_end().strip_prefix("data:") {
+                data.push(payload.trim_start().to_string());
+            }
+        }
+        data
+    }
+}
+
+impl TgBot {
+    /// Answer a question by streaming the completion into a message that is edited as the
+    /// answer grows. Falls back to a regular completion if the stream itself fails, the reply
+    /// is only finished once the answer is in. Returns the full answer
+    pub async fn answer_streaming(&self, message: &Message, query: &str) -> Result<Answer, Error> {
+        let to = Destination::reply_to(message);
+        let placeholder = self
+            .send_message(&to, bot_messages::STREAM_PLACEHOLDER)
+            .await?;
+
+        let (answer, shown) = match self
+            .stream_open_router(message, query, placeholder.message_id)
+            .await
+        {
+            Ok(streamed) => streamed,
+            // Asking again won't help when OpenRouter itself refused, the error is replied
+            // to the user instead of the placeholder
+            Err(e) if e.is_open_router() => {
+                if let Err(e) = self
+                    .delete_message(to.chat_id, placeholder.message_id)
+                    .await
+                {
+                    tracing::warn!(?e, "Failed to delete placeholder");
+                }
+                return Err(e);
+            }
+            Err(e) => {
+                tracing::warn!(?e, "Streaming failed, falling back to a regular completion");
+                (self.call_open_router(message, query).await?, String::new())
+            }
+        };
+        // Failing to show the answer is not a reason to ask the model again
+        self.finish_reply(&to, placeholder.message_id, &shown, &answer.text)
+            .await?;
+        Ok(answer)
+    }
+
+    /// Stream a completion, editing `message_id` with the text received so far at most once
+    /// every `stream_edit_interval`. While streaming only the part of the answer that fits in
+    /// one message is shown, returns the full answer together with what is shown
+    async fn stream_open_router(
+        &self,
+        message: &Message,
+        query: &str,
+        message_id: i64,
+    ) -> Result<(Answer, String), Error> {
+        let to = Destination::reply_to(message);
+        let request = self.completion_request(message, query, true);
+        let mut response = self.send_open_router(&request).await?;
+
+        let interval = Duration::from_millis(self.cfg().stream_edit_interval);
+        // A stream that stalls for this long is given up on
+        let idle = Duration::from_secs(self.cfg().open_router_timeout);
+        let mut parser = SseParser::default();
+        let mut answer = String::new();
+        let mut usage = None;
+        let mut shown = String::new();
+        let mut last_edit = Instant::now();
+
+        'stream: while let Some(chunk) = tokio::time::timeout(idle, response.chunk())
+            .await
+            .map_err(|_| Error::OpenRouterTimeout)??
+        {
+            for data in parser.push(&chunk) {
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+                let value: serde_json::Value = serde_json::from_str(&data)?;
+                if value.get("error").is_some() {
+                    return Err(serde_json::from_value::<ErrorResponse>(value)?.into());
+                }
+                let chunk: StreamChunk = serde_json::from_value(value)?;
+                usage = chunk.usage.or(usage);
+                for choice in chunk.choices {
+                    answer.push_str(&choice.delta.content.unwrap_or_default());
+                }
+            }
+
+            if last_edit.elapsed() >= interval {
+                let visible = split_message(&answer, TELEGRAM_MESSAGE_LIMIT)
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default();
+                if !visible.is_empty() && visible != shown {
+                    // A failed intermediate edit is not worth failing the answer for
+                    if let Err(e) = self
+                        .edit_message_markdown(to.chat_id, message_id, &visible)
+                        .await
+                    {
+                        tracing::warn!(?e, "Failed to update streamed answer");
+                    }
+                    shown = visible;
+                    last_edit = Instant::now();
+                }
+            }
+        }
+
+        let answer = Answer {
+            text: answer,
+            model: request.model,
+            usage,
+        };
+        Ok((answer, shown))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_parser_joins_split_lines() {
+        let mut parser = SseParser::default();
+        assert!(parser.push(b": OPENROUTER PROCESSING\n\n").is_empty());
+        assert!(parser.push(b"data: {\"a\":").is_empty());
+        assert_eq!(
+            parser.push(b" 1}\n\ndata: [DONE]\n"),
+            vec!["{\"a\": 1}", "[DONE]"]
+        );
+
+        // A multi-byte character split over two chunks survives
+        let bytes = "data: frög\n".as_bytes();
+        assert!(parser.push(&bytes[..9]).is_empty());
+        assert_eq!(parser.push(&bytes[9..]), vec!["frög"]);
+    }
+}
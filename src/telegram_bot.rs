@@ -1,22 +1,104 @@
+use crate::access::{Access, AccessLists, Target};
+use crate::chat_settings::Settings;
 use crate::commands;
+use crate::constants::{ALLOWED_UPDATES, TELEGRAM_MAX_RETRIES};
+use crate::dispatcher::Dispatcher;
 use crate::error;
+use crate::history::{estimate_tokens, Conversation, History};
+use crate::limits::{QuotaTracker, Quotas, RateLimiter};
 use crate::messages;
-use crate::model::Model;
-use commands::Command;
+use crate::messages::bot_messages;
+use crate::messages::telegram::{Destination, ErrorResponse, Message, Response, Update, User};
+use crate::model::{Model, ModelRegistry};
+use crate::open_router::Answer;
+use crate::params::SamplingParams;
+use crate::persona::{Persona, Personas};
+use crate::storage::{JsonFileStorage, MemoryStorage, Storage};
+use crate::usage::{Span, Totals, UsageLedger};
+use crate::utils::{days_since_epoch, Backoff};
 use commands::CommandTrait;
+use commands::{Command, CommandText};
 use error::Error;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Key the offset of the last handled update is stored under
+const OFFSET_KEY: &str = "offset";
 
 #[derive(Default)]
 pub struct Config {
     pub tg_bot_key: String,
     pub open_router_key: String,
-    pub polling_interval: u64,
+    pub polling_timeout: u64,
+    pub polling_limit: u8,
+    pub backoff_base: u64,
+    pub backoff_max: u64,
+    pub history_max_turns: usize,
+    pub history_max_tokens: usize,
+    pub default_model: String,
+    /// System prompt for chats that haven't set their own with `/system`
+    pub system_prompt: String,
+    /// Sampling parameters for chats that haven't set their own with `/params`
+    pub default_params: SamplingParams,
+    pub per_user_model: bool,
+    /// Users that manage access at runtime
+    pub admins: BTreeSet<i64>,
+    /// Access lists used until they are changed at runtime
+    pub access: AccessLists,
+    /// `/frog` requests a minute allowed per user and per chat
+    pub user_rate_limit: Option<u32>,
+    pub chat_rate_limit: Option<u32>,
+    /// Requests and tokens allowed per user per day and month
+    pub quotas: Quotas,
+    /// File the bot's state is persisted in, without one the state only lives in memory
+    pub state_file: Option<PathBuf>,
+    pub models_file: Option<PathBuf>,
+    pub personas_file: Option<PathBuf>,
+    pub max_concurrent_updates: usize,
+    /// Treat every message in a private chat that isn't a command as a question
+    pub private_prompts: bool,
+    /// Stream completions and edit the answer in place while it is generated
+    pub stream: bool,
+    /// Minimum time between two edits of a streamed answer, in milliseconds
+    pub stream_edit_interval: u64,
+    /// Seconds to wait for a completion before giving up
+    pub open_router_timeout: u64,
+    /// Send answers as a document when they would be split over more messages than this
+    pub document_after_chunks: Option<usize>,
+    /// Receive updates through a webhook instead of polling when set
+    pub webhook: Option<WebhookConfig>,
+}
+
+/// Where Telegram pushes updates to in webhook mode
+pub struct WebhookConfig {
+    /// Public url registered with `setWebhook`
+    pub url: String,
+    /// Address the embedded server listens on
+    pub listen: SocketAddr,
+    /// Secret Telegram sends back in every request, so we know it's really Telegram
+    pub secret: String,
 }
 
 pub struct TgBot {
     pub http_client: reqwest::Client,
-    model: Model,
-    offset: i64,
+    models: ModelRegistry,
+    default_model: Model,
+    personas: Personas,
+    storage: Arc<dyn Storage>,
+    access: Mutex<Access>,
+    rate_limiter: Mutex<RateLimiter>,
+    quotas: Mutex<QuotaTracker>,
+    usage: Mutex<UsageLedger>,
+    settings: Mutex<Settings>,
+    history: Mutex<History>,
+    offset: AtomicI64,
+    /// The bot's own user, learned with `getMe` at startup
+    me: Option<User>,
     cfg: Config,
 }
 
@@ -24,9 +106,19 @@ impl Default for TgBot {
     fn default() -> Self {
         TgBot {
             http_client: reqwest::Client::new(),
-            model: Model::default(),
+            models: ModelRegistry::default(),
+            default_model: Model::new("openai/gpt-4o"),
+            personas: Personas::default(),
+            storage: Arc::new(MemoryStorage::default()),
+            access: Mutex::default(),
+            rate_limiter: Mutex::default(),
+            quotas: Mutex::default(),
+            usage: Mutex::default(),
+            settings: Mutex::default(),
+            history: Mutex::default(),
             cfg: Config::default(),
-            offset: 0,
+            offset: AtomicI64::new(0),
+            me: None,
         }
     }
 }
@@ -36,16 +128,57 @@ impl TgBot {
     /// notation to fill in the missing fields in a predictable way.
     /// This is nice because now if we ever change the `Config` struct, we don't have to change the
     /// `new` function, we can just change the `Default` implementation.
-    pub fn new(cfg: Config) -> Self {
-        TgBot {
+    pub fn new(cfg: Config) -> Result<Self, Error> {
+        let models = match &cfg.models_file {
+            Some(path) => ModelRegistry::load(path)?,
+            None => ModelRegistry::default(),
+        };
+        let default_model = models
+            .resolve(&cfg.default_model)
+            .ok_or_else(|| Error::UnknownModel(cfg.default_model.clone()))?;
+        let personas = match &cfg.personas_file {
+            Some(path) => Personas::load(path)?,
+            None => Personas::default(),
+        };
+        // Catch typos in persona models at startup rather than when someone uses them
+        for model in personas.iter().filter_map(|persona| persona.model.as_ref()) {
+            models
+                .resolve(model)
+                .ok_or_else(|| Error::UnknownModel(model.clone()))?;
+        }
+        let storage: Arc<dyn Storage> = match &cfg.state_file {
+            Some(path) => Arc::new(JsonFileStorage::open(path.clone())?),
+            None => Arc::new(MemoryStorage::default()),
+        };
+        Ok(TgBot {
+            models,
+            default_model,
+            personas,
+            access: Mutex::new(Access::load(
+                storage.clone(),
+                cfg.access.clone(),
+                cfg.admins.clone(),
+            )?),
+            rate_limiter: Mutex::new(RateLimiter::new(cfg.user_rate_limit, cfg.chat_rate_limit)),
+            quotas: Mutex::new(QuotaTracker::load(storage.clone(), cfg.quotas)?),
+            usage: Mutex::new(UsageLedger::load(storage.clone())?),
+            settings: Mutex::new(Settings::load(storage.clone(), cfg.per_user_model)?),
+            history: Mutex::new(History::load(
+                storage.clone(),
+                cfg.history_max_turns,
+                cfg.history_max_tokens,
+            )?),
+            // Carry on after the last update handled before a restart
+            offset: AtomicI64::new(storage.load(OFFSET_KEY)?.unwrap_or(0)),
+            storage,
             cfg,
             ..Default::default()
-        }
+        })
     }
 
     /// Explicit **read only** methods for these fields
-    pub fn model(&self) -> Model {
-        self.model
+    pub fn cfg(&self) -> &Config {
+        &self.cfg
     }
     pub fn open_router_key(&self) -> &str {
         &self.cfg.open_router_key
@@ -53,68 +186,513 @@ impl TgBot {
     pub fn tg_bot_key(&self) -> &str {
         &self.cfg.tg_bot_key
     }
+    /// The bot's username, known once `fetch_me` has been called
+    pub fn username(&self) -> Option<&str> {
+        self.me.as_ref()?.username.as_deref()
+    }
+    pub fn models(&self) -> &ModelRegistry {
+        &self.models
+    }
+    pub fn personas(&self) -> &Personas {
+        &self.personas
+    }
+
+    pub fn is_admin(&self, user_id: i64) -> bool {
+        self.access
+            .lock()
+            .expect("access lock poisoned")
+            .is_admin(user_id)
+    }
+
+    /// Whether a user may use the bot in a chat
+    pub fn is_allowed(&self, user_id: i64, chat_id: i64) -> bool {
+        self.access
+            .lock()
+            .expect("access lock poisoned")
+            .is_allowed(user_id, chat_id)
+    }
+
+    /// Allow a user or chat to use the bot
+    pub fn allow(&self, target: Target) -> Result<(), Error> {
+        self.access
+            .lock()
+            .expect("access lock poisoned")
+            .allow(target)
+    }
 
-    /// Send a message to a chat
-    pub async fn send_message(&self, chat_id: i64, text: &str) -> Result<(), Error> {
+    /// Deny a user or chat the use of the bot
+    pub fn deny(&self, target: Target) -> Result<(), Error> {
+        self.access
+            .lock()
+            .expect("access lock poisoned")
+            .deny(target)
+    }
+
+    /// The admins and access lists, formatted for `/users`
+    pub fn access_lists(&self) -> String {
+        self.access.lock().expect("access lock poisoned").describe()
+    }
+
+    /// The model used for a user in a chat
+    /// Falls back to the model of the chat's persona and then to the configured default
+    pub fn model(&self, chat_id: i64, user_id: i64) -> Model {
+        let default = self
+            .persona(chat_id)
+            .and_then(|persona| persona.model)
+            .and_then(|model| self.models.resolve(&model))
+            .unwrap_or_else(|| self.default_model.clone());
+        self.settings
+            .lock()
+            .expect("settings lock poisoned")
+            .model(chat_id, user_id, &default)
+    }
+
+    /// The persona picked in a chat, if any
+    pub fn persona(&self, chat_id: i64) -> Option<Persona> {
+        let settings = self.settings.lock().expect("settings lock poisoned");
+        let name = settings.chat(chat_id)?.persona.as_ref()?;
+        self.personas.get(name).cloned()
+    }
+
+    /// Switch the persona of a chat
+    /// The chat's own system prompt and model, and the models its users picked, are dropped, so
+    /// the persona's are used
+    pub fn set_persona(&self, chat_id: i64, name: &str) -> Result<Persona, Error> {
+        let persona = self
+            .personas
+            .get(name)
+            .ok_or_else(|| Error::UnknownPersona(name.to_string()))?;
+        self.settings
+            .lock()
+            .expect("settings lock poisoned")
+            .update(chat_id, |chat| {
+                chat.persona = Some(persona.name.clone());
+                chat.system_prompt = None;
+                chat.model = None;
+                chat.users.clear();
+            })?;
+        Ok(persona.clone())
+    }
+
+    /// The system prompt of a chat
+    /// Falls back to the prompt of the chat's persona and then to the configured one
+    pub fn system_prompt(&self, chat_id: i64) -> String {
+        let custom = self
+            .settings
+            .lock()
+            .expect("settings lock poisoned")
+            .chat(chat_id)
+            .and_then(|chat| chat.system_prompt.clone());
+        custom
+            .or_else(|| self.persona(chat_id).map(|persona| persona.system_prompt))
+            .unwrap_or_else(|| self.cfg.system_prompt.clone())
+    }
+
+    /// Set the system prompt of a chat, `None` goes back to the configured one
+    pub fn set_system_prompt(&self, chat_id: i64, prompt: Option<String>) -> Result<(), Error> {
+        self.settings
+            .lock()
+            .expect("settings lock poisoned")
+            .update(chat_id, |chat| chat.system_prompt = prompt)
+    }
+
+    /// The sampling parameters of a chat
+    /// Parameters set with `/params` take precedence over the persona's temperature, which
+    /// takes precedence over the configured defaults
+    pub fn sampling_params(&self, chat_id: i64) -> SamplingParams {
+        let persona = SamplingParams {
+            temperature: self
+                .persona(chat_id)
+                .and_then(|persona| persona.temperature),
+            ..Default::default()
+        };
+        let chat = self
+            .settings
+            .lock()
+            .expect("settings lock poisoned")
+            .chat(chat_id)
+            .map(|chat| chat.params.clone())
+            .unwrap_or_default();
+        self.cfg.default_params.merge(&persona).merge(&chat)
+    }
+
+    /// Change the sampling parameters of a chat
+    /// Nothing is changed when `change` fails, e.g. because a value is out of range
+    pub fn update_params(
+        &self,
+        chat_id: i64,
+        change: impl FnOnce(&mut SamplingParams) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut settings = self.settings.lock().expect("settings lock poisoned");
+        let mut params = settings
+            .chat(chat_id)
+            .map(|chat| chat.params.clone())
+            .unwrap_or_default();
+        change(&mut params)?;
+        settings.update(chat_id, |chat| chat.params = params)
+    }
+
+    /// The earlier exchanges of a chat, or forum topic, as OpenRouter messages
+    pub fn conversation(&self, conversation: Conversation) -> Vec<messages::openrouter::Message> {
+        self.history
+            .lock()
+            .expect("history lock poisoned")
+            .messages(conversation)
+    }
+
+    /// Check whether a user may ask a question right now, and count it
+    /// Fails when the user or chat hit the rate limit, or the user used up a quota
+    pub fn check_limits(&self, chat_id: i64, user_id: i64) -> Result<(), Error> {
+        self.rate_limiter
+            .lock()
+            .expect("rate limiter lock poisoned")
+            .acquire(chat_id, user_id, Instant::now())
+            .map_err(Error::RateLimited)?;
+        self.quotas
+            .lock()
+            .expect("quotas lock poisoned")
+            .start_request(user_id, SystemTime::now())
+    }
+
+    /// Give back the quota `check_limits` counted for a question that wasn't answered
+    /// The rate limit isn't given back, failed questions still cost OpenRouter calls
+    pub fn refund_limits(&self, user_id: i64) {
+        let result = self
+            .quotas
+            .lock()
+            .expect("quotas lock poisoned")
+            .refund_request(user_id, SystemTime::now());
+        if let Err(e) = result {
+            tracing::error!(?e, "Failed to persist quota usage");
+        }
+    }
+
+    /// Count what answering the question in `message` used and remember the exchange
+    /// The tokens are estimated when OpenRouter didn't report them
+    pub fn record_answer(&self, message: &Message, question: &str, answer: &Answer) {
+        let (chat_id, user_id) = (message.chat.get_id(), message.from.id);
+        let totals = match &answer.usage {
+            Some(usage) => Totals {
+                requests: 1,
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                cost: usage.cost.unwrap_or_default(),
+            },
+            None => Totals {
+                requests: 1,
+                prompt_tokens: estimate_tokens(question) as u64,
+                completion_tokens: estimate_tokens(&answer.text) as u64,
+                cost: 0.0,
+            },
+        };
+        let now = SystemTime::now();
+        let result = self
+            .quotas
+            .lock()
+            .expect("quotas lock poisoned")
+            .add_tokens(user_id, totals.tokens(), now);
+        if let Err(e) = result {
+            tracing::error!(?e, "Failed to persist quota usage");
+        }
+        let result = self.usage.lock().expect("usage lock poisoned").record(
+            days_since_epoch(now),
+            chat_id,
+            user_id,
+            &answer.model,
+            &totals,
+        );
+        if let Err(e) = result {
+            tracing::error!(?e, "Failed to persist usage");
+        }
+        self.remember(Conversation::of(message), question, &answer.text);
+    }
+
+    /// What a user and a chat used today, this month and all time, formatted for `/usage`
+    /// The chat is left out in private chats, where it's the same as the user
+    pub fn describe_usage(&self, chat_id: i64, user_id: i64, private: bool) -> String {
+        let usage = self.usage.lock().expect("usage lock poisoned");
+        let today = days_since_epoch(SystemTime::now());
+        let spans = [Span::Today, Span::Month, Span::AllTime];
+        let mut text = String::from("your usage:");
+        for span in spans {
+            let totals = usage.user(user_id, span, today);
+            text.push_str(&format!("\n- {}: {}", span.name(), totals.describe()));
+        }
+        if !private {
+            text.push_str("\n\nthis chat:");
+            for span in spans {
+                let totals = usage.chat(chat_id, span, today);
+                text.push_str(&format!("\n- {}: {}", span.name(), totals.describe()));
+            }
+        }
+        text
+    }
+
+    /// Everything used within a span, formatted for `/usage global`
+    pub fn usage_report(&self, span: Span) -> String {
+        self.usage
+            .lock()
+            .expect("usage lock poisoned")
+            .report(span, days_since_epoch(SystemTime::now()))
+    }
+
+    /// Remember a `/frog` exchange so follow-up questions in the same chat have context
+    /// The answer has already been sent, so failing to persist it is only logged
+    pub fn remember(&self, conversation: Conversation, question: &str, answer: &str) {
+        let result = self.history.lock().expect("history lock poisoned").push(
+            conversation,
+            question,
+            answer,
+        );
+        if let Err(e) = result {
+            tracing::error!(?e, "Failed to persist history");
+        }
+    }
+
+    /// Call a method of the Telegram bot API
+    /// `request` adds the parameters to the request, it's called again for every attempt.
+    /// When Telegram answers with 429 Too Many Requests the call is retried after the
+    /// `retry_after` Telegram asks for, up to `TELEGRAM_MAX_RETRIES` times
+    pub async fn call_telegram<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        request: impl Fn(reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder, Error>,
+    ) -> Result<T, Error> {
         let url = format!(
-            "https://api.telegram.org/bot{}/sendMessage",
+            "https://api.telegram.org/bot{}/{}",
             self.tg_bot_key(),
+            method
         );
-        let body = serde_json::json!({
+        let mut attempt = 0;
+        loop {
+            let response: serde_json::Value = request(self.http_client.post(&url))?
+                .send()
+                .await?
+                .json()
+                .await?;
+            if response["ok"].as_bool() == Some(true) {
+                let response: Response<T> = serde_json::from_value(response)?;
+                return Ok(response.result);
+            }
+
+            let error = Error::from(serde_json::from_value::<ErrorResponse>(response)?);
+            match error {
+                Error::Telegram {
+                    error_code: 429,
+                    retry_after: Some(retry_after),
+                    ..
+                } if attempt < TELEGRAM_MAX_RETRIES => {
+                    attempt += 1;
+                    tracing::warn!(method, retry_after, attempt, "Rate limited by Telegram");
+                    tokio::time::sleep(Duration::from_secs(retry_after)).await;
+                }
+                error => return Err(error),
+            }
+        }
+    }
+
+    /// Send a message, returns the message as it was sent
+    pub async fn send_message(&self, to: &Destination, text: &str) -> Result<Message, Error> {
+        self.send_text(to, text, None).await
+    }
+
+    /// Send a message whose text is formatted as `parse_mode`, e.g. `HTML`
+    pub async fn send_text(
+        &self,
+        to: &Destination,
+        text: &str,
+        parse_mode: Option<&str>,
+    ) -> Result<Message, Error> {
+        let mut body = serde_json::to_value(to)?;
+        body["text"] = text.into();
+        if let Some(parse_mode) = parse_mode {
+            body["parse_mode"] = parse_mode.into();
+        }
+        self.call_telegram("sendMessage", |request| Ok(request.json(&body)))
+            .await
+    }
+
+    /// Send a file
+    pub async fn send_document(
+        &self,
+        to: &Destination,
+        file_name: &str,
+        contents: Vec<u8>,
+        caption: &str,
+    ) -> Result<(), Error> {
+        let _: Message = self
+            .call_telegram("sendDocument", |request| {
+                let file = reqwest::multipart::Part::bytes(contents.clone())
+                    .file_name(file_name.to_string())
+                    .mime_str("text/markdown")?;
+                let mut form = reqwest::multipart::Form::new()
+                    .text("chat_id", to.chat_id.to_string())
+                    .text("caption", caption.to_string())
+                    .part("document", file);
+                // Multipart fields are plain text, objects are passed as JSON
+                if let Some(topic_id) = to.topic_id {
+                    form = form.text("message_thread_id", topic_id.to_string());
+                }
+                if let Some(reply_to) = &to.reply_to {
+                    form = form.text("reply_parameters", serde_json::to_string(reply_to)?);
+                }
+                Ok(request.multipart(form))
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Replace the text of a message the bot sent earlier
+    pub async fn edit_message_text(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        text: &str,
+    ) -> Result<(), Error> {
+        self.edit_text(chat_id, message_id, text, None).await
+    }
+
+    /// Replace the text of a message the bot sent earlier with text formatted as `parse_mode`
+    pub async fn edit_text(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        text: &str,
+        parse_mode: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut body = serde_json::json!({
             "chat_id": chat_id,
+            "message_id": message_id,
             "text": text,
         });
-        self.http_client.post(&url).json(&body).send().await?;
+        if let Some(parse_mode) = parse_mode {
+            body["parse_mode"] = parse_mode.into();
+        }
+        let _: Message = self
+            .call_telegram("editMessageText", |request| Ok(request.json(&body)))
+            .await?;
+        Ok(())
+    }
+
+    /// Delete a message from a chat
+    pub async fn delete_message(&self, chat_id: i64, message_id: i64) -> Result<(), Error> {
+        let body = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id,
+        });
+        let _: bool = self
+            .call_telegram("deleteMessage", |request| Ok(request.json(&body)))
+            .await?;
         Ok(())
     }
 
     /// Get all updates from the telegram bot
-    pub async fn get_updates(&self) -> Result<messages::telegram::Response, Error> {
+    /// Long polls, Telegram answers as soon as there are updates or after `polling_timeout`
+    pub async fn get_updates(&self) -> Result<Vec<Update>, Error> {
         tracing::debug!("getting updates");
-        let url = format!(
-            "https://api.telegram.org/bot{}/getUpdates?offset={}",
-            self.tg_bot_key(),
-            self.offset + 1
-        );
-        Ok(self.http_client.get(&url).send().await?.json().await?)
+        let body = serde_json::json!({
+            "offset": self.offset.load(Ordering::Relaxed) + 1,
+            "timeout": self.cfg.polling_timeout,
+            "limit": self.cfg.polling_limit,
+            "allowed_updates": ALLOWED_UPDATES,
+        });
+        // Give Telegram some slack on top of the long polling timeout before giving up
+        let timeout = Duration::from_secs(self.cfg.polling_timeout + 10);
+        self.call_telegram("getUpdates", |request| {
+            Ok(request.json(&body).timeout(timeout))
+        })
+        .await
     }
 
-    /// Change model
-    pub fn change_model(&mut self, model: &str) {
-        if let Ok(model) = Model::try_from(model) {
-            self.model = model;
-        }
+    /// Change model for a chat, or for a user in a chat when models are selected per user
+    /// Accepts an alias from the registry or an exact model slug
+    pub fn change_model(&self, chat_id: i64, user_id: i64, model: &str) -> Result<(), Error> {
+        let model = self
+            .models
+            .resolve(model)
+            .ok_or_else(|| Error::UnknownModel(model.to_string()))?;
+        self.settings
+            .lock()
+            .expect("settings lock poisoned")
+            .set_model(chat_id, user_id, model)
     }
 
-    pub async fn run(&mut self) -> Result<(), Error> {
+    /// Learn who the bot is, the username tells commands meant for us apart in groups
+    pub async fn fetch_me(&mut self) -> Result<(), Error> {
+        let me: User = self.call_telegram("getMe", Ok).await?;
+        tracing::info!(username = me.username, "running as");
+        self.me = Some(me);
+        Ok(())
+    }
+
+    /// Refresh the model registry from OpenRouter
+    pub async fn refresh_models(&mut self) -> Result<(), Error> {
+        self.models.refresh(&self.http_client).await
+    }
+
+    /// Receive updates, either from the webhook or by polling, and hand every message to the
+    /// dispatcher. The messages are handled concurrently, see `Dispatcher`
+    pub async fn run(self) -> Result<(), Error> {
+        let bot = Arc::new(self);
+        let dispatcher = Dispatcher::new(bot.clone(), bot.cfg.max_concurrent_updates);
+        if bot.cfg.webhook.is_some() {
+            return bot.serve_webhook(dispatcher).await;
+        }
+
+        // A webhook left behind by an earlier run makes `getUpdates` fail
+        bot.delete_webhook().await?;
+        let mut backoff = Backoff::new(bot.cfg.backoff_base, bot.cfg.backoff_max);
         loop {
-            match self.get_updates().await {
-                Ok(response) => {
-                    for update in response.result {
-                        self.offset = update.update_id;
-                        match update.message {
-                            None => {}
-                            _ => match self.handle_update(&update.message.unwrap()).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    tracing::error!(?e, "Failed to handle update");
-                                }
-                            },
+            match bot.get_updates().await {
+                Ok(updates) => {
+                    backoff.reset();
+                    let Some(last) = updates.last().map(|update| update.update_id) else {
+                        continue;
+                    };
+                    for update in updates {
+                        bot.offset.store(update.update_id, Ordering::Relaxed);
+                        if let Some(message) = update.message {
+                            dispatcher.dispatch(message);
                         }
                     }
+                    if let Err(e) = bot.storage.store(OFFSET_KEY, &last) {
+                        tracing::error!(?e, "Failed to persist the update offset");
+                    }
                 }
                 Err(e) => {
-                    tracing::error!(?e, "Failed to get updates");
+                    let delay = backoff.next_delay();
+                    tracing::error!(?e, ?delay, "Failed to get updates, retrying");
+                    tokio::time::sleep(delay).await;
                 }
             }
-            std::thread::sleep(std::time::Duration::from_millis(self.cfg.polling_interval));
         }
     }
 
-    pub async fn handle_update(
-        &mut self,
-        update: &messages::telegram::Message,
-    ) -> Result<(), Error> {
+    /// Whether a user is the bot itself
+    pub fn is_me(&self, user: &User) -> bool {
+        self.me.as_ref().is_some_and(|me| me.id == user.id)
+    }
+
+    /// Text that isn't a command but is still a question for the bot
+    /// In private chats that's every message when `private_prompts` is enabled. In groups the
+    /// bot has to be mentioned, which is left out of the question, or replied to
+    fn plain_prompt(&self, message: &Message, text: &str) -> Option<String> {
+        if message.chat.is_private() {
+            return self.cfg.private_prompts.then(|| text.trim().to_string());
+        }
+        if let Some(prompt) = self
+            .username()
+            .and_then(|username| message.without_mention(username))
+        {
+            return Some(prompt);
+        }
+        message
+            .replied_to()
+            .filter(|reply| self.is_me(&reply.from))
+            .map(|_| text.trim().to_string())
+    }
+
+    pub async fn handle_update(&self, update: &messages::telegram::Message) -> Result<(), Error> {
         tracing::debug!(?update, "handling update");
         match update.text {
             None => {
@@ -124,10 +702,54 @@ impl TgBot {
             _ => {
                 let text = update.text.as_ref().expect("must be text");
                 tracing::debug!(?text, "handling update: ");
-                Command::try_from(text.as_ref())
-                    .expect("unknown command")
-                    .execute(self, update.chat.get_id())
-                    .await
+                let command = match CommandText::parse(text, update.entities.as_deref()) {
+                    Some(command) if !command.is_for(self.username()) => {
+                        tracing::debug!(bot = command.bot, "command for another bot");
+                        return Ok(());
+                    }
+                    Some(command) => Command::try_from(command),
+                    None => match self
+                        .plain_prompt(update, text)
+                        .filter(|prompt| !prompt.is_empty())
+                    {
+                        Some(prompt) => Ok(Command::Frog(prompt)),
+                        None => {
+                            tracing::debug!("not a command");
+                            return Ok(());
+                        }
+                    },
+                };
+                if !self.is_allowed(update.from.id, update.chat.get_id()) {
+                    tracing::info!(
+                        user_id = update.from.id,
+                        chat_id = update.chat.get_id(),
+                        "ignoring message from user without access"
+                    );
+                    // Tell people talking to the bot directly why it stays quiet
+                    if update.chat.is_private() {
+                        self.send_message(
+                            &Destination::reply_to(update),
+                            bot_messages::NOT_ALLOWED,
+                        )
+                        .await?;
+                    }
+                    return Ok(());
+                }
+                let result = match command {
+                    Ok(command) => command.execute(self, update).await,
+                    Err(e) => Err(e),
+                };
+
+                // Let the user know why they didn't get an answer
+                if let Some(reply) = result.as_ref().err().and_then(Error::user_message) {
+                    if let Err(e) = self
+                        .send_message(&Destination::reply_to(update), &reply)
+                        .await
+                    {
+                        tracing::error!(?e, "Failed to send error reply");
+                    }
+                }
+                result
             }
         }
     }
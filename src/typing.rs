@@ -0,0 +1,42 @@
+use std::future::Future;
+
+use crate::constants::TYPING_INTERVAL;
+use crate::error::Error;
+use crate::messages::telegram::Destination;
+use crate::telegram_bot::TgBot;
+
+impl TgBot {
+    /// Show an action, like `typing`, in the chat and topic of `to` for a few seconds
+    pub async fn send_chat_action(&self, to: &Destination, action: &str) -> Result<(), Error> {
+        let mut body = serde_json::json!({
+            "chat_id": to.chat_id,
+            "action": action,
+        });
+        if let Some(topic_id) = to.topic_id {
+            body["message_thread_id"] = topic_id.into();
+        }
+        let _: bool = self
+            .call_telegram("sendChatAction", |request| Ok(request.json(&body)))
+            .await?;
+        Ok(())
+    }
+
+    /// Run `future` while showing the bot as typing in the chat of `to`
+    /// The action is refreshed every `TYPING_INTERVAL` alongside the future, and stops as soon
+    /// as it completes or fails
+    pub async fn with_typing<T>(&self, to: &Destination, future: impl Future<Output = T>) -> T {
+        let refresh = async {
+            loop {
+                // Only a hint for the user, not worth failing the request for
+                if let Err(e) = self.send_chat_action(to, "typing").await {
+                    tracing::warn!(?e, "Failed to send typing action");
+                }
+                tokio::time::sleep(TYPING_INTERVAL).await;
+            }
+        };
+        tokio::select! {
+            result = future => result,
+            _ = refresh => unreachable!("the typing action is refreshed until the future is done"),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::model::Model;
+use crate::storage::{MemoryStorage, Storage};
+use crate::utils::month_of;
+
+/// Key the usage of the current month is stored under, per day
+const STORAGE_KEY: &str = "usage";
+
+/// Key the usage of earlier months is stored under, per month
+const MONTHS_KEY: &str = "usage_months";
+
+/// How many of the biggest users, chats and models the global report lists
+const REPORT_TOP: usize = 5;
+
+/// What one or more completions used
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Totals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// In OpenRouter credits (US dollars)
+    pub cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+
+    pub fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// One line summary, e.g. `3 questions, 1200 tokens (900 in, 300 out), $0.0042`
+    pub fn describe(&self) -> String {
+        format!(
+            "{} questions, {} tokens ({} in, {} out), ${:.4}",
+            self.requests,
+            self.tokens(),
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.cost
+        )
+    }
+}
+
+/// What was used on a single day (UTC), or in a whole month, per user, per chat and per model
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct DayUsage {
+    #[serde(default)]
+    users: HashMap<i64, Totals>,
+    #[serde(default)]
+    chats: HashMap<i64, Totals>,
+    #[serde(default)]
+    models: HashMap<Model, Totals>,
+}
+
+impl DayUsage {
+    fn add(&mut self, other: &DayUsage) {
+        add_all(&mut self.users, &other.users);
+        add_all(&mut self.chats, &other.chats);
+        add_all(&mut self.models, &other.models);
+    }
+}
+
+fn add_all<K: Clone + Eq + Hash>(into: &mut HashMap<K, Totals>, from: &HashMap<K, Totals>) {
+    for (key, totals) in from {
+        into.entry(key.clone()).or_default().add(totals);
+    }
+}
+
+/// The period `/usage` reports on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Span {
+    Today,
+    Month,
+    AllTime,
+}
+
+impl Span {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Span::Today => "today",
+            Span::Month => "this month",
+            Span::AllTime => "all time",
+        }
+    }
+
+    /// Whether `day` falls within the span, as seen from `today`
+    fn contains(&self, day: i64, today: i64) -> bool {
+        match self {
+            Span::Today => day == today,
+            Span::Month => month_of(day) == month_of(today),
+            Span::AllTime => true,
+        }
+    }
+
+    /// Whether a whole month falls within the span, as seen from `today`
+    fn contains_month(&self, month: i64, today: i64) -> bool {
+        match self {
+            Span::Today => false,
+            Span::Month => month == month_of(today),
+            Span::AllTime => true,
+        }
+    }
+}
+
+/// Usage of every completion, kept per day for the current month and per month before that,
+/// so it can be reported on for any span without growing by the day
+/// Persisted so the numbers survive a restart
+#[derive(Debug)]
+pub struct UsageLedger {
+    /// Keyed by days since the unix epoch
+    days: BTreeMap<i64, DayUsage>,
+    /// Keyed by `year * 12 + month - 1`
+    months: BTreeMap<i64, DayUsage>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for UsageLedger {
+    fn default() -> Self {
+        UsageLedger {
+            days: BTreeMap::new(),
+            months: BTreeMap::new(),
+            storage: Arc::new(MemoryStorage::default()),
+        }
+    }
+}
+
+impl UsageLedger {
+    pub fn load(storage: Arc<dyn Storage>) -> Result<Self, Error> {
+        Ok(UsageLedger {
+            days: storage.load(STORAGE_KEY)?.unwrap_or_default(),
+            months: storage.load(MONTHS_KEY)?.unwrap_or_default(),
+            storage,
+        })
+    }
+
+    /// Add up the days before the month of `today` into their months
+    /// Returns whether there was anything to roll up
+    fn roll_up(&mut self, today: i64) -> bool {
+        let this_month = month_of(today);
+        let past: Vec<i64> = self
+            .days
+            .keys()
+            .copied()
+            .filter(|day| month_of(*day) < this_month)
+            .collect();
+        for day in &past {
+            if let Some(usage) = self.days.remove(day) {
+                self.months.entry(month_of(*day)).or_default().add(&usage);
+            }
+        }
+        !past.is_empty()
+    }
+
+    /// Add what a completion used and persist it
+    pub fn record(
+        &mut self,
+        day: i64,
+        chat_id: i64,
+        user_id: i64,
+        model: &Model,
+        totals: &Totals,
+    ) -> Result<(), Error> {
+        let usage = self.days.entry(day).or_default();
+        usage.users.entry(user_id).or_default().add(totals);
+        usage.chats.entry(chat_id).or_default().add(totals);
+        usage.models.entry(model.clone()).or_default().add(totals);
+        if self.roll_up(day) {
+            self.storage.store(MONTHS_KEY, &self.months)?;
+        }
+        self.storage.store(STORAGE_KEY, &self.days)
+    }
+
+    /// The days and months within a span
+    fn periods(&self, span: Span, today: i64) -> impl Iterator<Item = &DayUsage> {
+        let months = self
+            .months
+            .iter()
+            .filter(move |(month, _)| span.contains_month(**month, today));
+        self.days
+            .iter()
+            .filter(move |(day, _)| span.contains(**day, today))
+            .chain(months)
+            .map(|(_, usage)| usage)
+    }
+
+    /// What a user used within a span
+    pub fn user(&self, user_id: i64, span: Span, today: i64) -> Totals {
+        sum(self
+            .periods(span, today)
+            .filter_map(|usage| usage.users.get(&user_id)))
+    }
+
+    /// What was used in a chat within a span
+    pub fn chat(&self, chat_id: i64, span: Span, today: i64) -> Totals {
+        sum(self
+            .periods(span, today)
+            .filter_map(|usage| usage.chats.get(&chat_id)))
+    }
+
+    /// Everything used within a span, with the biggest users, chats and models, for admins
+    pub fn report(&self, span: Span, today: i64) -> String {
+        let days: Vec<&DayUsage> = self.periods(span, today).collect();
+        let total = sum(days.iter().flat_map(|usage| usage.models.values()));
+        let mut report = format!("usage {}: {}", span.name(), total.describe());
+        let sections = [
+            ("models", top(days.iter().map(|usage| &usage.models))),
+            ("users", top(days.iter().map(|usage| &usage.users))),
+            ("chats", top(days.iter().map(|usage| &usage.chats))),
+        ];
+        for (name, rows) in sections {
+            if rows.is_empty() {
+                continue;
+            }
+            report.push_str(&format!("\n\ntop {}:", name));
+            for (key, totals) in rows {
+                report.push_str(&format!("\n- {}: {}", key, totals.describe()));
+            }
+        }
+        report
+    }
+}
+
+fn sum<'a>(totals: impl Iterator<Item = &'a Totals>) -> Totals {
+    totals.fold(Totals::default(), |mut sum, totals| {
+        sum.add(totals);
+        sum
+    })
+}
+
+/// Add up the totals of every key over all days, and keep the most expensive ones
+fn top<'a, K>(maps: impl Iterator<Item = &'a HashMap<K, Totals>>) -> Vec<(String, Totals)>
+where
+    K: Eq + Hash + ToString + 'a,
+{
+    let mut totals: HashMap<String, Totals> = HashMap::new();
+    for (key, value) in maps.flatten() {
+        totals.entry(key.to_string()).or_default().add(value);
+    }
+    let mut rows: Vec<(String, Totals)> = totals.into_iter().collect();
+    rows.sort_by(|(a_key, a), (b_key, b)| {
+        b.cost
+            .total_cmp(&a.cost)
+            .then(b.tokens().cmp(&a.tokens()))
+            .then(a_key.cmp(b_key))
+    });
+    rows.truncate(REPORT_TOP);
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_per_span() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut ledger = UsageLedger::load(storage.clone()).unwrap();
+        let gpt = Model::new("openai/gpt-4o");
+        let claude = Model::new("anthropic/claude-3.5-sonnet");
+        let question = |cost| Totals {
+            requests: 1,
+            prompt_tokens: 100,
+            completion_tokens: 50,
+            cost,
+        };
+        // 2024-01-31, 2024-02-01 and 2024-02-02
+        ledger.record(19_753, 1, 10, &gpt, &question(0.5)).unwrap();
+        ledger
+            .record(19_754, 1, 10, &claude, &question(1.0))
+            .unwrap();
+        ledger.record(19_755, 2, 20, &gpt, &question(0.25)).unwrap();
+
+        let today = 19_755;
+        assert_eq!(ledger.user(10, Span::Today, today), Totals::default());
+        assert_eq!(ledger.user(10, Span::Month, today).requests, 1);
+        assert_eq!(ledger.user(10, Span::AllTime, today).tokens(), 300);
+        assert_eq!(ledger.chat(2, Span::Today, today).cost, 0.25);
+
+        // January is rolled up into a month once February has begun
+        assert_eq!((ledger.days.len(), ledger.months.len()), (2, 1));
+
+        // Persisted, and the most expensive model is listed first
+        let ledger = UsageLedger::load(storage).unwrap();
+        let report = ledger.report(Span::AllTime, today);
+        assert!(report.starts_with("usage all time: 3 questions, 450 tokens"));
+        assert!(report.find("anthropic").unwrap() < report.find("openai").unwrap());
+    }
+}
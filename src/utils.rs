@@ -1,3 +1,7 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
 /// Initialize logger
 /// Uses env filter from default env
 /// Example
@@ -10,3 +14,136 @@ pub fn init_logger() -> tracing_subscriber::EnvFilter {
         .init();
     tracing_subscriber::EnvFilter::from_default_env()
 }
+
+/// Exponential backoff with full jitter
+/// Every consecutive failure doubles the upper bound of the delay, up to `max` milliseconds.
+/// The actual delay is picked at random below that bound so retries don't line up
+pub struct Backoff {
+    base: u64,
+    max: u64,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: u64, max: u64) -> Self {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// The delay before the next retry
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(1 << self.attempt.min(32))
+            .min(self.max);
+        self.attempt += 1;
+        Duration::from_millis(rand::rng().random_range(0..=ceiling))
+    }
+
+    /// Start over after a success
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Seconds in a day, days are counted in UTC
+pub const SECS_PER_DAY: u64 = 86_400;
+
+/// Days since the unix epoch at `time`, in UTC
+pub fn days_since_epoch(time: SystemTime) -> i64 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / SECS_PER_DAY) as i64
+}
+
+/// The calendar date (year, month, day) of a day since the unix epoch
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+pub fn date_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The month a day since the unix epoch falls in, counted as `year * 12 + month - 1`
+pub fn month_of(day: i64) -> i64 {
+    let (year, month, _) = date_from_days(day);
+    year * 12 + i64::from(month) - 1
+}
+
+/// Days since the unix epoch of a calendar date, the inverse of `date_from_days`
+pub fn days_from_date(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Short human readable duration, e.g. `2d 5h`, `3h 20m` or `45s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs().max(1);
+    let (days, hours, minutes) = (secs / 86_400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m", minutes),
+        (0, _, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// Entity offsets count UTF-16 code units, turn one into a byte offset into `text`
+pub fn utf16_to_byte(text: &str, offset: i64) -> Option<usize> {
+    let mut units = 0;
+    for (index, c) in text.char_indices() {
+        if units == offset {
+            return Some(index);
+        }
+        units += c.len_utf16() as i64;
+    }
+    (units == offset).then_some(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_stays_within_bounds() {
+        let mut backoff = Backoff::new(100, 1000);
+        for ceiling in [100, 200, 400, 800, 1000, 1000] {
+            assert!(backoff.next_delay() <= Duration::from_millis(ceiling));
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_calendar_dates() {
+        assert_eq!(date_from_days(0), (1970, 1, 1));
+        // 2024-02-29, a leap day
+        assert_eq!(date_from_days(19_782), (2024, 2, 29));
+        assert_eq!(days_from_date(2024, 3, 1), 19_783);
+        assert_eq!(days_from_date(2025, 1, 1), 20_089);
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 3600 + 20 * 60)),
+            "3h 20m"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+
+use crate::constants::ALLOWED_UPDATES;
+use crate::dispatcher::Dispatcher;
+use crate::error::Error;
+use crate::messages::telegram::Update;
+use crate::telegram_bot::{TgBot, WebhookConfig};
+
+/// Header Telegram puts the secret token from `setWebhook` in
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+#[derive(Clone)]
+struct WebhookState {
+    dispatcher: Dispatcher,
+    secret: String,
+}
+
+impl TgBot {
+    fn webhook_config(&self) -> Result<&WebhookConfig, Error> {
+        self.cfg()
+            .webhook
+            .as_ref()
+            .ok_or_else(|| Error::Config("webhook mode is not configured".to_string()))
+    }
+
+    /// Register the webhook url with Telegram, updates are pushed to it from then on
+    pub async fn set_webhook(&self) -> Result<(), Error> {
+        let webhook = self.webhook_config()?;
+        let body = serde_json::json!({
+            "url": webhook.url,
+            "secret_token": webhook.secret,
+            "allowed_updates": ALLOWED_UPDATES,
+        });
+        let _: bool = self
+            .call_telegram("setWebhook", |request| Ok(request.json(&body)))
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the webhook, `getUpdates` refuses to work while one is set
+    pub async fn delete_webhook(&self) -> Result<(), Error> {
+        let _: bool = self.call_telegram("deleteWebhook", Ok).await?;
+        Ok(())
+    }
+
+    /// Register the webhook and serve it until the server fails
+    /// Every update Telegram posts is handed to the dispatcher, just like polled updates
+    pub async fn serve_webhook(self: Arc<Self>, dispatcher: Dispatcher) -> Result<(), Error> {
+        let webhook = self.webhook_config()?;
+        // Telegram posts to the exact url, so listen on its path
+        let path = reqwest::Url::parse(&webhook.url)
+            .map_err(|e| Error::Config(format!("invalid webhook url: {}", e)))?
+            .path()
+            .to_string();
+
+        let state = WebhookState {
+            dispatcher,
+            secret: webhook.secret.clone(),
+        };
+        let app = Router::new()
+            .route(&path, post(receive_update))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind(webhook.listen).await?;
+
+        self.set_webhook().await?;
+        tracing::info!(listen = %webhook.listen, path, "serving webhook");
+        axum::serve(listener, app).await?;
+        Ok(())
+    }
+}
+
+/// Compare a token with the secret in constant time, so the time it takes doesn't tell how
+/// much of a guess was right. Only the length can be learned, which isn't secret
+fn secrets_match(token: &str, secret: &str) -> bool {
+    token.len() == secret.len()
+        && token
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Accept an update from Telegram after checking it carries our secret token
+async fn receive_update(
+    State(state): State<WebhookState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let token = headers
+        .get(SECRET_TOKEN_HEADER)
+        .and_then(|token| token.to_str().ok());
+    if !token.is_some_and(|token| secrets_match(token, &state.secret)) {
+        tracing::warn!("rejected webhook request with a wrong secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let update: Update = match serde_json::from_slice(&body) {
+        Ok(update) => update,
+        // Refusing it would make Telegram send it again and again, holding back the updates
+        // after it, so it's dropped like updates without a message
+        Err(e) => {
+            let body = String::from_utf8_lossy(&body);
+            tracing::error!(?e, %body, "Failed to parse webhook update, dropping it");
+            return StatusCode::OK;
+        }
+    };
+    if let Some(message) = update.message {
+        state.dispatcher.dispatch(message);
+    }
+    StatusCode::OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match("s3cret-token", "s3cret-token"));
+        assert!(!secrets_match("s3cret-tokem", "s3cret-token"));
+        assert!(!secrets_match("s3cret", "s3cret-token"));
+        assert!(!secrets_match("", "s3cret-token"));
+    }
+}